@@ -0,0 +1,51 @@
+//! Times a few thousand objects chasing each other, to see how fast the
+//! interpreter is.
+//!
+//! Run with `cargo run --release --example many_objects [COUNT [EVENTS]]`.
+
+extern crate flop;
+
+use std::env;
+use std::time::Instant;
+
+use flop::prelude::*;
+
+use flop::data::Field;
+use flop::event::EventQueue;
+use flop::instance::FlopInstance;
+use flop::load_types::get_types;
+
+fn main() {
+    let mut args = env::args().skip(1).map(|arg| arg.parse().unwrap());
+    let count: f64 = args.next().unwrap_or(2000.0);
+    let events: f64 = args.next().unwrap_or(200_000.0);
+
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/many_objects");
+    let content = get_types(dir).unwrap();
+    let now = Time::try_from(0.0).unwrap();
+    let totem = unsafe { Totem::new() };
+    let mut game = FlopInstance::new(
+        totem,
+        EventQueue::new(now),
+        content,
+    );
+
+    let start = Instant::now();
+    let _root = FlopInstance::start(
+        &mut game,
+        "Swarm".into(),
+        "init".into(),
+        vec![Field::Num(count)],
+    ).unwrap();
+    let events = FlopInstance::step(&mut game, events as usize);
+    let elapsed = start.elapsed();
+
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    println!(
+        "{} objects, {} events in {:.3}s ({:.0} events/s)",
+        count,
+        events,
+        secs,
+        events as f64 / secs,
+    );
+}
@@ -1,14 +1,50 @@
-use prelude::*;
+use std::rc::Rc;
 
+use item;
 use runtime;
+use symbol::{Interner, Symbol, SymbolMap};
 
 pub struct Algorithm {
+    // byte offset of the parameter list
+    pub offset: usize,
     pub param_list: Vec<String>,
-    pub steps: Vec<Statement>,
+    // `None` for parameters without an annotation
+    pub param_types: Vec<Option<item::FieldType>>,
+    // `None` if the algorithm does not declare what it returns
+    pub return_types: Option<Vec<item::FieldType>>,
+    pub steps: Block,
 }
 
 pub type TablePath = runtime::TablePath;
 
+// each statement is paired with the byte offset it starts at
+pub type Block = Vec<(usize, Statement)>;
+
+/// Maps byte offsets in a source file to line numbers.
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let mut starts = vec![0];
+        for (i, c) in source.char_indices() {
+            if c == '\n' {
+                starts.push(i + 1);
+            }
+        }
+        LineIndex { starts }
+    }
+
+    /// 1-based line containing the given byte offset
+    pub fn line(self: &Self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Statement {
     Bang,
@@ -19,16 +55,17 @@ pub enum Statement {
     State(Expression),
     WhileLoop {
         condition: Expression,
-        block: Vec<Statement>,
+        block: Block,
     },
     Branch {
-        if_branches: Vec<(Expression, Vec<Statement>)>,
-        else_branch: Vec<Statement>,
+        if_branches: Vec<(Expression, Block)>,
+        else_branch: Block,
     },
     Match {
         data: Expression,
-        arms: Vec<(String, Vec<String>, Vec<Statement>)>,
-        def: Vec<Statement>,
+        arms: Vec<(String, Vec<String>, Block)>,
+        // `None` if there is no `_` arm
+        def: Option<Block>,
     },
 }
 
@@ -47,15 +84,22 @@ pub enum Expression {
     },
 
     Const(f64),
+    Bool(bool),
     Comparison(Box<Expression>, Vec<(CompareOp, Expression)>),
+    // the right operand is only evaluated if it decides the result
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Neg(Box<Expression>),
     Add(Box<Expression>, Box<Expression>),
     Sub(Box<Expression>, Box<Expression>),
     Mul(Box<Expression>, Box<Expression>),
     Div(Box<Expression>, Box<Expression>),
+    Mod(Box<Expression>, Box<Expression>),
     Pow(Box<Expression>, Box<Expression>),
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum CompareOp {
     Equals,
     NEquals,
@@ -65,250 +109,476 @@ pub enum CompareOp {
     Less,
 }
 
-pub fn convert_algorithm(alg: Algorithm) -> runtime::Algorithm {
-    let param_list = alg.param_list;
-    let steps = convert_statements(alg.steps);
-    runtime::Algorithm { param_list, steps }
+/// What an algorithm needs to know about the class it is compiled in.
+pub struct Context<'a> {
+    pub type_name: &'a Rc<str>,
+    pub source_name: &'a Rc<str>,
+    pub lines: &'a LineIndex,
+    // ids of the class's algorithms, for calls on `self`
+    pub algorithms: &'a SymbolMap<usize>,
+}
+
+/// Code that can't be compiled, which the type checker catches in classes,
+/// but not in code typed into the repl.
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
 }
 
-fn convert_statements(steps: Vec<Statement>) -> Vec<runtime::Statement> {
-    let mut result = Vec::new();
-    for x in steps.into_iter() {
-        convert_statement(x, &mut result);
+pub fn convert_algorithm(
+    alg: Algorithm,
+    name: &str,
+    context: &Context,
+    symbols: &mut Interner,
+) -> Result<runtime::Algorithm, CompileError> {
+    let param_count = alg.param_list.len();
+    let locals = alg.param_list
+        .iter()
+        .map(|param| symbols.intern(param))
+        .collect();
+    let mut compiler = Compiler {
+        context,
+        symbols,
+        code: Vec::new(),
+        lines: Vec::new(),
+        starts: Vec::new(),
+        waits: Vec::new(),
+        locals,
+        line: 0,
+        start: false,
+        error: None,
+    };
+    compiler.block(alg.steps);
+    if let Some(error) = compiler.error {
+        return Err(error);
     }
-    result
+
+    Ok(runtime::Algorithm {
+        name: Rc::from(name),
+        type_name: Rc::clone(context.type_name),
+        symbol: compiler.symbols.intern(name),
+        type_symbol: compiler.symbols.intern(context.type_name),
+        param_count,
+        locals: compiler.locals,
+        code: compiler.code,
+        lines: compiler.lines,
+        starts: compiler.starts,
+        waits: compiler.waits,
+        source_name: Rc::clone(context.source_name),
+    })
 }
 
-fn convert_statement(step: Statement, result: &mut Vec<runtime::Statement>) {
-    use self::Statement::*;
-    let converted = match step {
-        Bang => runtime::Statement::Debug("BANG".into()),
-        Evaluate { mut expressions, results } => {
-            if results.len() == 0 {
-                if expressions.len() == 1 {
-                    use self::Expression::Method;
-                    if let Method { names, args } = &mut expressions[0] {
-                        let converted = convert_simple_statement(names, args);
-                        if let Some(converted) = converted {
-                            result.push(converted);
-                            return;
+struct Compiler<'a> {
+    context: &'a Context<'a>,
+    symbols: &'a mut Interner,
+    code: Vec<runtime::Op>,
+    lines: Vec<usize>,
+    starts: Vec<bool>,
+    waits: Vec<(usize, runtime::WaitLabel)>,
+    locals: Vec<Symbol>,
+    // where the statement being compiled came from
+    line: usize,
+    start: bool,
+    // the first thing that couldn't be compiled, if any, in which case the
+    // code is thrown away
+    error: Option<CompileError>,
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(self: &mut Self, op: runtime::Op) -> usize {
+        self.code.push(op);
+        self.lines.push(self.line);
+        self.starts.push(self.start);
+        self.start = false;
+        self.code.len() - 1
+    }
+
+    // points a jump emitted earlier at the next op
+    fn patch(self: &mut Self, index: usize) {
+        let here = self.code.len();
+        match self.code[index] {
+            runtime::Op::Branch(ref mut target)
+            | runtime::Op::Jump(ref mut target)
+            | runtime::Op::CompareAnd(_, ref mut target)
+            | runtime::Op::ShortCircuit(_, ref mut target) => *target = here,
+            _ => unreachable!(),
+        }
+    }
+
+    fn fail(self: &mut Self, message: String) {
+        if self.error.is_none() {
+            let line = self.line;
+            self.error = Some(CompileError { line, message });
+        }
+    }
+
+    fn symbol(self: &mut Self, name: &str) -> Symbol {
+        self.symbols.intern(name)
+    }
+
+    fn slot(self: &mut Self, name: &str) -> usize {
+        let name = self.symbol(name);
+        if let Some(slot) = self.locals.iter().position(|&x| x == name) {
+            return slot;
+        }
+        self.locals.push(name);
+        self.locals.len() - 1
+    }
+
+    fn block(self: &mut Self, steps: Block) {
+        for (offset, step) in steps {
+            let line = self.context.lines.line(offset);
+            self.statement(step, line);
+        }
+    }
+
+    fn statement(self: &mut Self, step: Statement, line: usize) {
+        use self::Statement::*;
+        self.line = line;
+        self.start = true;
+        match step {
+            Bang => {
+                self.emit(runtime::Op::Debug("BANG".into()));
+            },
+            Evaluate { mut expressions, results } => {
+                if results.len() == 0 {
+                    if expressions.len() == 1 {
+                        use self::Expression::Method;
+                        if let Method { names, args } = &mut expressions[0] {
+                            if self.simple_statement(names, args) {
+                                return;
+                            }
                         }
+                    } else {
+                        let message = "separate statements with ';', not ','";
+                        self.fail(message.into());
+                        return;
                     }
-                } else {
-                    panic!("Separate statements with semicolon not comma");
                 }
-            }
 
-            runtime::Statement::Evaluate {
-                expressions: convert_expressions(expressions),
-                results,
-            }
-        },
-        State(state) => runtime::Statement::State(convert_expression(state)),
-        WhileLoop {
-            condition,
-            block,
-        } => {
-            // could extend, insert, push to avoid unnecessary heap allocs
-            // might not be faster tho
-            let condition = convert_expression(condition);
-            let block = convert_statements(block);
-            let block_len = block.len();
-            let break_offset = block_len + 2;
-            result.push(runtime::Statement::Branch {
+                self.emit(runtime::Op::Mark);
+                self.expressions(expressions);
+                let slots = results
+                    .iter()
+                    .map(|name| self.slot(name))
+                    .collect();
+                self.emit(runtime::Op::Store(slots));
+            },
+            State(state) => {
+                self.single(state);
+                self.emit(runtime::Op::State);
+            },
+            WhileLoop {
                 condition,
-                break_offset,
-            });
-            result.extend(block);
-            result.push(runtime::Statement::Continue(block_len + 1));
-            return;
-        },
-        Branch { mut if_branches, else_branch } => {
-            let mut rest = convert_statements(else_branch);
-            let mut blocks = Vec::with_capacity(if_branches.len() + 1);
-            for (cond, block) in if_branches {
-                let condition = convert_expression(cond);
-                let mut block = convert_statements(block);
-
-                // we could also store the conditions and put a noop into the
-                // blocks... but its going to branch when it drops so we might
-                // as well branch to check anyway
-                let statement = runtime::Statement::Branch {
-                    condition,
-                    break_offset: 0,
-                };
-                block.insert(0, statement);
-
-                blocks.push(block);
-            }
-            blocks.push(rest);
-            let (mut codes, offsets) = link_blocks(blocks, 0);
-            for i in 0..offsets.len()-2 {
-                if let runtime::Statement::Branch {
-                    ref mut break_offset,
-                    ..
-                } = &mut codes[offsets[i]] {
-                    *break_offset = offsets[i+1] - offsets[i];
-                } else {
-                    unreachable!();
+                block,
+            } => {
+                let top = self.code.len();
+                self.single(condition);
+                let branch = self.emit(runtime::Op::Branch(0));
+                self.block(block);
+                self.line = line;
+                self.emit(runtime::Op::Jump(top));
+                self.patch(branch);
+            },
+            Branch { if_branches, else_branch } => {
+                let mut exits = Vec::with_capacity(if_branches.len());
+                for (condition, block) in if_branches {
+                    self.line = line;
+                    self.single(condition);
+                    let branch = self.emit(runtime::Op::Branch(0));
+                    self.block(block);
+                    self.line = line;
+                    exits.push(self.emit(runtime::Op::Jump(0)));
+                    self.patch(branch);
                 }
-            }
-            result.extend(codes);
-            return;
-        },
-        Match { data, arms, def } => {
-            let arms_len = arms.len();
-            let mut blocks = Vec::with_capacity(arms_len);
-            let mut new_arms = Dict::with_capacity(arms_len);
-            for (i, (variant, fields, block)) in arms.into_iter().enumerate() {
-                let block = convert_statements(block);
-                blocks.push(block);
-                new_arms.insert(variant, (fields, i));
-            }
-            let def = convert_statements(def);
-            blocks.push(def);
-
-            let (codes, offsets) = link_blocks(blocks, 1);
-            for (_, (_, ref mut i)) in &mut new_arms {
-                let index = *i;
-                *i = offsets[index];
-            }
+                self.block(else_branch);
+                for exit in exits {
+                    self.patch(exit);
+                }
+            },
+            Match { data, arms, def } => {
+                self.single(data);
+                let index = self.emit(runtime::Op::Match {
+                    arms: SymbolMap::new(),
+                    default: 0,
+                });
 
-            // offsets = [branch, branch, ..., default, continue]
-            // so the number of branches is the index of the default
-            let default_offset = offsets[arms_len];
-
-            let data = convert_expression(data);
-            let statement = runtime::Statement::PatternBranch {
-                data,
-                arms: new_arms,
-                default_offset,
-            };
-            result.push(statement);
-            result.extend(codes);
-            return;
-        },
-    };
-    result.push(converted);
-}
+                let mut targets = SymbolMap::with_capacity(arms.len());
+                let mut exits = Vec::with_capacity(arms.len());
+                for (variant, fields, block) in arms {
+                    let target = self.code.len();
+                    let bindings = fields
+                        .iter()
+                        .map(|field| (self.symbol(field), self.slot(field)))
+                        .collect();
+                    let variant = self.symbol(&variant);
+                    targets.insert(variant, (bindings, target));
+                    self.block(block);
+                    self.line = line;
+                    exits.push(self.emit(runtime::Op::Jump(0)));
+                }
+                let default_target = self.code.len();
+                self.block(def.unwrap_or(Vec::new()));
+                for exit in exits {
+                    self.patch(exit);
+                }
 
-fn link_blocks(
-    blocks: Vec<Vec<runtime::Statement>>,
-    initial_offset: usize,
-) -> (Vec<runtime::Statement>, Vec<usize>) {
-    let mut offset = initial_offset;
-    let mut offsets = Vec::with_capacity(blocks.len() + 1);
-    for block in &blocks {
-        offsets.push(offset);
-        offset += block.len() + 1;
-    }
-    offsets.push(offset);
-    let mut result = Vec::with_capacity(offset - initial_offset);
-    for (i, block) in blocks.into_iter().enumerate() {
-        let next_offset = offsets[i+1];
-        result.extend(block);
-        result.push(runtime::Statement::Jump(offset - next_offset + 1));
+                if let runtime::Op::Match {
+                    ref mut arms,
+                    ref mut default,
+                } = self.code[index] {
+                    *arms = targets;
+                    *default = default_target;
+                }
+            },
+        }
     }
-    (result, offsets)
-}
 
-// if this returns `None` then it didn't modify the inputs
-fn convert_simple_statement(names: &mut Vec<String>, args: &mut Vec<Expression>)
-    -> Option<runtime::Statement>
-{
-    if names.len() == 1 {
+    // builtins that are statements of their own, returning false if `names`
+    // isn't one, in which case the inputs are left alone
+    fn simple_statement(
+        self: &mut Self,
+        names: &mut Vec<String>,
+        args: &mut Vec<Expression>,
+    ) -> bool {
+        if names.len() != 1 {
+            return false;
+        }
         if names[0] == "print" {
             let args = ::std::mem::replace(args, Vec::new());
-            return Some(runtime::Statement::DebugNums(
-                convert_expressions(args)
-            ));
+            self.emit(runtime::Op::Mark);
+            self.expressions(args);
+            self.emit(runtime::Op::Print);
         } else if names[0] == "wait" {
-            assert!(args.len() == 1, "wait expects 1 argument");
+            if args.len() != 1 {
+                self.fail("wait expects 1 argument".into());
+                return true;
+            }
             let arg = args.pop().unwrap();
-            return Some(runtime::Statement::Wait(convert_expression(arg)));
+            self.single(arg);
+            let state = self.wait_state();
+            let index = self.waits
+                .iter()
+                .filter(|&&(_, label)| label.state == state)
+                .count();
+            let pc = self.emit(runtime::Op::Wait);
+            self.waits.push((pc, runtime::WaitLabel { state, index }));
+        } else if names[0] == "trace" {
+            if args.len() != 0 {
+                self.fail("trace expects no arguments".into());
+                return true;
+            }
+            self.emit(runtime::Op::Trace);
         } else if names[0] == "return" {
             let args = ::std::mem::replace(args, Vec::new());
-            let args = convert_expressions(args);
-            return Some(runtime::Statement::Return(args));
+            self.emit(runtime::Op::Mark);
+            self.expressions(args);
+            self.emit(runtime::Op::Return);
+        } else {
+            return false;
         }
+        true
     }
-    None
-}
 
-fn convert_expressions(vals: Vec<Expression>) -> Vec<runtime::Expression> {
-    vals.into_iter()
-        .map(convert_expression)
-        .collect()
-}
+    // the state literal assigned by the statement right before the one being
+    // compiled, if that is what it did
+    fn wait_state(self: &Self) -> Option<Symbol> {
+        let start = self.starts.iter().rposition(|&start| start)?;
+        if start < 2 {
+            return None;
+        }
+        match (&self.code[start - 2], &self.code[start - 1]) {
+            (&runtime::Op::Data { name, .. }, &runtime::Op::State) => {
+                Some(name)
+            },
+            _ => None,
+        }
+    }
+
+    // pushes however many values the expressions give
+    fn expressions(self: &mut Self, vals: Vec<Expression>) {
+        for val in vals {
+            self.expression(val);
+        }
+    }
 
-fn convert_expression(val: Expression) -> runtime::Expression {
-    use self::Expression::*;
-    match val {
-        Var(name) => runtime::Expression::Var(name),
-        Method {
-            names,
-            args,
-        } => {
-            let args = convert_expressions(args);
-            if names.len() == 1 {
-                assert!(args.len() == 1,
-                    "Object virtualization expects 1 argument");
-                runtime::Expression::VirtualizeObject {
-                    interface_name: names[0].clone(),
-                    object: Box::new({args}.pop().unwrap()),
+    // pushes exactly one value, or fails at runtime
+    fn single(self: &mut Self, val: Expression) {
+        let is_call = match val {
+            Expression::Method { ref names, .. } => {
+                names.len() > 1 && !(names[0] == "Set" && names[1] == "new")
+            },
+            _ => false,
+        };
+        if is_call {
+            self.emit(runtime::Op::Mark);
+            self.expression(val);
+            self.emit(runtime::Op::Single);
+        } else {
+            self.expression(val);
+        }
+    }
+
+    // `l and r` when `decides` is false, `l or r` when it is true
+    fn short_circuit(
+        self: &mut Self,
+        l: Expression,
+        r: Expression,
+        decides: bool,
+    ) {
+        self.single(l);
+        let exit = self.emit(runtime::Op::ShortCircuit(decides, 0));
+        self.single(r);
+        self.patch(exit);
+    }
+
+    fn binary(self: &mut Self, l: Expression, r: Expression, op: runtime::Op) {
+        self.single(l);
+        self.single(r);
+        self.emit(op);
+    }
+
+    fn expression(self: &mut Self, val: Expression) {
+        use self::Expression::*;
+        match val {
+            Var(name) => {
+                let slot = self.slot(&name);
+                self.emit(runtime::Op::Load(slot));
+            },
+            Method {
+                mut names,
+                mut args,
+            } => {
+                if names.len() == 1 {
+                    if args.len() != 1 {
+                        self.fail(format!(
+                            "{}(..) expects 1 argument, got {}",
+                            names[0],
+                            args.len(),
+                        ));
+                        return;
+                    }
+                    self.single(args.pop().unwrap());
+                    let interface_name = self.symbol(&names[0]);
+                    self.emit(runtime::Op::Virtualize(interface_name));
+                    return;
                 }
-            } else if names.len() == 2 {
-                if names[0] == "Set" && names[1] == "new" {
-                    assert!(args.len() == 0, "Set.new expects no arguments");
-                    runtime::Expression::InitSet
-                } else if names[0] == "game" {
-                    runtime::Expression::ExternCall {
-                        function_name: names[1].clone(),
-                        args,
+                if names.len() == 2 && names[0] == "Set" && names[1] == "new" {
+                    if args.len() != 0 {
+                        self.fail("Set.new expects no arguments".into());
+                        return;
+                    }
+                    self.emit(runtime::Op::InitSet);
+                    return;
+                }
+
+                self.emit(runtime::Op::Mark);
+                self.expressions(args);
+                let op = if names.len() == 2 {
+                    let action_name = names.pop().unwrap();
+                    let object_name = names.pop().unwrap();
+                    // type names can have a module in front
+                    let is_type = object_name
+                        .rsplit('.')
+                        .next()
+                        .and_then(|name| name.chars().next())
+                        .map_or(false, char::is_uppercase);
+                    if object_name == "game" {
+                        runtime::Op::ExternCall(action_name)
+                    } else if object_name == "self" {
+                        let action_name = self.symbol(&action_name);
+                        match self.context.algorithms.get(&action_name) {
+                            Some(&id) => {
+                                runtime::Op::ExecSelf(id, action_name)
+                            },
+                            None => runtime::Op::ExecSelfNamed(action_name),
+                        }
+                    } else if is_type {
+                        runtime::Op::InitConcrete {
+                            type_name: self.symbol(&object_name),
+                            init_name: self.symbol(&action_name),
+                        }
+                    } else {
+                        let slot = self.slot(&object_name);
+                        let action_name = self.symbol(&action_name);
+                        runtime::Op::ExecLocal { slot, action_name }
+                    }
+                } else if names.len() == 3 {
+                    let init_name = self.symbol(&names[2]);
+                    let table_name = self.symbol(&names[1]);
+                    let type_name = self.symbol(&names[0]);
+                    runtime::Op::InitObject {
+                        type_name,
+                        table_name,
+                        init_name,
                     }
                 } else {
-                    runtime::Expression::ExecObject {
-                        object_name: names[0].clone(),
-                        action_name: names[1].clone(),
-                        args,
+                    let path = names.join(".");
+                    self.fail(format!("'{}' is too long a path", path));
+                    return;
+                };
+                self.emit(op);
+            },
+            SelfObject => {
+                self.emit(runtime::Op::SelfObject);
+            },
+            SelfData => {
+                self.emit(runtime::Op::SelfData);
+            },
+            Data { name, fields } => {
+                let mut names = Vec::with_capacity(fields.len());
+                for (field, val) in fields {
+                    names.push(self.symbol(&field));
+                    self.single(val);
+                }
+                let name = self.symbol(&name);
+                self.emit(runtime::Op::Data { name, fields: names });
+            },
+
+            Const(x) => {
+                self.emit(runtime::Op::Const(x));
+            },
+            Bool(x) => {
+                self.emit(runtime::Op::Bool(x));
+            },
+            Comparison(l, r) => {
+                self.single(*l);
+                let last = r.len() - 1;
+                let mut exits = Vec::with_capacity(last);
+                for (i, (op, r)) in r.into_iter().enumerate() {
+                    self.single(r);
+                    if i == last {
+                        self.emit(runtime::Op::Compare(op));
+                    } else {
+                        exits.push(self.emit(runtime::Op::CompareAnd(op, 0)));
                     }
                 }
-            } else if names.len() == 3 {
-                runtime::Expression::InitObject {
-                    type_name: names[0].clone(),
-                    table_name: names[1].clone(),
-                    init_name: names[2].clone(),
-                    args,
+                for exit in exits {
+                    self.patch(exit);
                 }
-            } else {
-                panic!("Too much stuff");
-            }
-        },
-        SelfObject => runtime::Expression::SelfObject,
-        SelfData => runtime::Expression::SelfData,
-        Data { name, fields } => {
-            let fields = fields
-                .into_iter()
-                .map(|(name, val)| (name, convert_expression(val)))
-                .collect();
-            runtime::Expression::Data { name, fields }
-        },
-
-        Const(f64) => runtime::Expression::Const(f64),
-        Comparison(l, r) => runtime::Expression::Comparison(
-            box_convert(l),
-            r.into_iter()
-             .map(|(c, r)|(c, convert_expression(r)))
-             .collect(),
-        ),
-        Add(l, r) => runtime::Expression::Add(box_convert(l), box_convert(r)),
-        Sub(l, r) => runtime::Expression::Sub(box_convert(l), box_convert(r)),
-        Mul(l, r) => runtime::Expression::Mul(box_convert(l), box_convert(r)),
-        Div(l, r) => runtime::Expression::Div(box_convert(l), box_convert(r)),
-        Pow(l, r) => runtime::Expression::Pow(box_convert(l), box_convert(r)),
+            },
+            And(l, r) => self.short_circuit(*l, *r, false),
+            Or(l, r) => self.short_circuit(*l, *r, true),
+            Not(x) => {
+                self.single(*x);
+                self.emit(runtime::Op::Not);
+            },
+            // negative literals are just constants
+            Neg(x) => match *x {
+                Const(x) => {
+                    self.emit(runtime::Op::Const(-x));
+                },
+                x => {
+                    self.single(x);
+                    self.emit(runtime::Op::Neg);
+                },
+            },
+            Add(l, r) => self.binary(*l, *r, runtime::Op::Add),
+            Sub(l, r) => self.binary(*l, *r, runtime::Op::Sub),
+            Mul(l, r) => self.binary(*l, *r, runtime::Op::Mul),
+            Div(l, r) => self.binary(*l, *r, runtime::Op::Div),
+            Mod(l, r) => self.binary(*l, *r, runtime::Op::Mod),
+            Pow(l, r) => self.binary(*l, *r, runtime::Op::Pow),
+        }
     }
 }
-
-fn box_convert(val: Box<Expression>) -> Box<runtime::Expression> {
-    Box::new(convert_expression(*val))
-}
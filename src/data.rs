@@ -1,8 +1,11 @@
-use std::hash::{Hash, Hasher};
+use std::collections::btree_map::{self, BTreeMap};
+use std::collections::HashMap;
 
 use prelude::*;
 
+use error::FlopError;
 use event;
+use symbol::{self, Interner, Symbol, SymbolMap};
 
 // we could use a union
 #[derive(Clone)]
@@ -10,7 +13,7 @@ pub enum Field {
     Num(f64),
     VRef(ObjectRef),
     TRef(Object),
-    Data(String, Data),
+    Data(Symbol, Data),
     // Weak(WeakRef),
     // List(???),
     Set(ObjectSet),
@@ -18,83 +21,156 @@ pub enum Field {
 
 impl Field {
     pub fn from_bool(val: bool) -> Self {
-        Field::Data(if val { "True" } else { "False" }.into(), Dict::new())
+        let name = if val { symbol::TRUE } else { symbol::FALSE };
+        Field::Data(name, Data::new())
     }
-    pub fn num(self: &Self) -> f64 {
+
+    /// Describes the kind of value held, for error messages.
+    pub fn describe(self: &Self, symbols: &Interner) -> String {
+        match *self {
+            Field::Data(name, _) => format!("data '{}'", symbols.name(name)),
+            _ => self.kind().into(),
+        }
+    }
+
+    // what `describe` says, short of naming the variant of data
+    fn kind(self: &Self) -> &'static str {
+        match *self {
+            Field::Num(_) => "a number",
+            Field::VRef(_) | Field::TRef(_) => "an object",
+            Field::Data(..) => "data",
+            Field::Set(_) => "a set",
+        }
+    }
+
+    fn mismatch(self: &Self, expected: &'static str) -> FlopError {
         match *self {
-            Field::Num(result) => result,
-            _ => panic!("Expected number"),
+            Field::Data(variant, _) => {
+                FlopError::DataMismatch { expected, variant }
+            },
+            _ => {
+                let found = self.kind().into();
+                FlopError::TypeMismatch { expected, found }
+            },
         }
     }
 
-    pub fn tref(self: &Self) -> &Object {
+    pub fn num(self: &Self) -> Result<f64, FlopError> {
         match *self {
-            Field::TRef(ref result) => result,
-            _ => panic!("Expected object"),
+            Field::Num(result) => Ok(result),
+            _ => Err(self.mismatch("a number")),
         }
     }
 
-    pub fn vref(self: &Self) -> &ObjectRef {
+    pub fn tref(self: &Self) -> Result<&Object, FlopError> {
         match *self {
-            Field::VRef(ref result) => result,
-            _ => panic!("Expected object"),
+            Field::TRef(ref result) => Ok(result),
+            _ => Err(self.mismatch("an object")),
         }
     }
 
-    pub fn unwrap_tref(self: Self) -> Object {
+    pub fn vref(self: &Self) -> Result<&ObjectRef, FlopError> {
+        match *self {
+            Field::VRef(ref result) => Ok(result),
+            _ => Err(self.mismatch("an object")),
+        }
+    }
+
+    pub fn unwrap_tref(self: Self) -> Result<Object, FlopError> {
         match self {
-            Field::TRef(result) => result,
-            _ => panic!("Expected object"),
+            Field::TRef(result) => Ok(result),
+            _ => Err(self.mismatch("an object")),
         }
     }
 
-    pub fn unwrap_vref(self: Self) -> ObjectRef {
+    pub fn unwrap_vref(self: Self) -> Result<ObjectRef, FlopError> {
         match self {
-            Field::VRef(result) => result,
-            _ => panic!("Expected object"),
+            Field::VRef(result) => Ok(result),
+            _ => Err(self.mismatch("an object")),
         }
     }
 
-    pub fn unwrap_data(self: Self) -> (String, Data) {
+    pub fn unwrap_data(self: Self) -> Result<(Symbol, Data), FlopError> {
         match self {
-            Field::Data(name, data) => (name, data),
-            _ => panic!("Expected data"),
+            Field::Data(name, data) => Ok((name, data)),
+            _ => Err(self.mismatch("data")),
         }
     }
 
-    pub fn bool(self: &Self) -> bool {
-        match *self {
-            Field::Data(ref name, ref data) => {
-                if data.len() != 0 {
-                    panic!("Bool can not have fields");
+    pub fn bool(self: &Self) -> Result<bool, FlopError> {
+        if let Field::Data(name, ref data) = *self {
+            if data.len() == 0 {
+                if name == symbol::TRUE {
+                    return Ok(true);
                 }
-                if name == "True" {
-                    return true;
+                if name == symbol::FALSE {
+                    return Ok(false);
                 }
-                if name == "False" {
-                    return false;
-                }
-                panic!("Bool is either True or False");
-            },
-            _ => panic!("Expected data (bool)"),
+            }
         }
+        Err(self.mismatch("True {} or False {}"))
     }
 
-    pub fn unwrap_set(self: Self) -> ObjectSet {
+    pub fn unwrap_set(self: Self) -> Result<ObjectSet, FlopError> {
         match self {
-            Field::Set(result) => result,
-            _ => panic!("Expected set"),
+            Field::Set(result) => Ok(result),
+            _ => Err(self.mismatch("a set")),
         }
     }
 
-    pub fn set(self: &mut Self) -> &mut ObjectSet {
+    pub fn set(self: &mut Self) -> Result<&mut ObjectSet, FlopError> {
         match *self {
-            Field::Set(ref mut result) => result,
-            _ => panic!("Expected set"),
+            Field::Set(ref mut result) => Ok(result),
+            _ => Err(self.mismatch("a set")),
         }
     }
 }
 
+/// Formats a value for people to read.
+pub fn show_field(
+    totem: &Totem,
+    symbols: &Interner,
+    field: &Field,
+) -> String {
+    match *field {
+        Field::Num(num) => num.to_string(),
+        Field::TRef(ref object) => {
+            let object = object.borrow(totem);
+            format!(
+                "<{} in {}>",
+                symbols.name(object.type_name),
+                symbols.name(object.state_name),
+            )
+        },
+        Field::VRef(ref object) => {
+            let table = object.table;
+            let object = object.data.borrow(totem);
+            format!(
+                "<{}.{} in {}>",
+                symbols.name(object.type_name),
+                symbols.name(table),
+                symbols.name(object.state_name),
+            )
+        },
+        Field::Data(name, ref fields) => {
+            let fields: Vec<String> = sorted_fields(symbols, fields)
+                .into_iter()
+                .map(|(field, value)| {
+                    let value = show_field(totem, symbols, value);
+                    format!("{} = {}", field, value)
+                })
+                .collect();
+            let name = symbols.name(name);
+            if fields.len() == 0 {
+                format!("{} {{}}", name)
+            } else {
+                format!("{} {{ {} }}", name, fields.join(", "))
+            }
+        },
+        Field::Set(ref set) => format!("<set of {}>", set.len()),
+    }
+}
+
 /*
 struct DataTerm {
     variant: String,
@@ -102,7 +178,20 @@ struct DataTerm {
 }
 */
 
-pub type Data = Dict<Field>;
+pub type Data = SymbolMap<Field>;
+
+/// The fields of some data with their names, in alphabetical order.
+pub fn sorted_fields<'a>(
+    symbols: &'a Interner,
+    data: &'a Data,
+) -> Vec<(&'a str, &'a Field)> {
+    let mut fields: Vec<_> = data
+        .iter()
+        .map(|(&name, value)| (symbols.name(name), value))
+        .collect();
+    fields.sort_by_key(|&(name, _)| name);
+    fields
+}
 
 pub type Object = Strong<ObjectData>;
 
@@ -110,17 +199,17 @@ pub struct ObjectData {
     // for cancelling the current wait timer
     pub event: Option<event::EventHandle>,
 
-    pub type_name: String,
+    pub type_name: Symbol,
     // purely for saving to file
-    pub state_name: String,
+    pub state_name: Symbol,
 
     pub data: Data,
 }
 
 impl ObjectData {
-    pub fn new(type_name: String) -> Object {
-        let data = Dict::new();
-        let state_name = "EMPTY".into();
+    pub fn new(type_name: Symbol) -> Object {
+        let data = Data::new();
+        let state_name = symbol::EMPTY;
         let event = None;
         let object = ObjectData { event, type_name, state_name, data };
         strong(object)
@@ -129,49 +218,73 @@ impl ObjectData {
 
 #[derive(Clone)]
 pub struct ObjectRef {
-    pub table: String,
+    pub table: Symbol,
     pub data: Object,
 }
 
+/// Identifies an object for as long as it is alive.
+pub fn address(object: &Object) -> usize {
+    let as_ref = &**object;
+    as_ref as *const Cell<ObjectData> as usize
+}
 
+/// A set of objects, kept in the order they were added, so that what comes
+/// out of it doesn't depend on where objects happen to be in memory, and
+/// survives a save.
 #[derive(Clone)]
-pub struct ObjectKey(pub ObjectRef);
+pub struct ObjectSet {
+    // insertion number -> object
+    order: BTreeMap<u64, ObjectRef>,
+    // object address -> insertion number
+    index: HashMap<usize, u64>,
+    next: u64,
+}
 
-impl ObjectKey {
-    fn as_usize(self: &Self) -> usize {
-        let as_ref = &*self.0.data;
-        as_ref as *const Cell<ObjectData> as usize
+impl ObjectSet {
+    pub fn new() -> Self {
+        ObjectSet {
+            order: BTreeMap::new(),
+            index: HashMap::new(),
+            next: 0,
+        }
     }
-}
 
-impl Hash for ObjectKey {
-    fn hash<H: Hasher>(self: &Self, state: &mut H) {
-        self.as_usize().hash(state);
+    pub fn len(self: &Self) -> usize {
+        self.order.len()
     }
-}
 
-impl PartialEq for ObjectKey {
-    fn eq(self: &Self, other: &Self) -> bool {
-        self.as_usize() == other.as_usize()
+    pub fn is_empty(self: &Self) -> bool {
+        self.order.is_empty()
     }
-}
 
-impl Eq for ObjectKey {
-}
+    /// Adds an object at the end, unless it is already in the set.
+    pub fn insert(self: &mut Self, object: ObjectRef) {
+        let address = address(&object.data);
+        if self.index.contains_key(&address) {
+            return;
+        }
+        self.index.insert(address, self.next);
+        self.order.insert(self.next, object);
+        self.next += 1;
+    }
 
-pub type ObjectSet = ::std::collections::HashMap<ObjectKey, ()>;
+    pub fn remove(self: &mut Self, object: &ObjectRef) {
+        if let Some(number) = self.index.remove(&address(&object.data)) {
+            self.order.remove(&number);
+        }
+    }
 
-pub fn set_pop(set: &mut ObjectSet) -> Option<(ObjectRef, ())> {
-    let mut out = None;
-    for (x, y) in &*set {
-        out = Some((x.clone(), y.clone()));
-        break;
+    /// Takes out the object that has been in the set longest.
+    pub fn pop(self: &mut Self) -> Option<ObjectRef> {
+        let number = *self.order.keys().next()?;
+        let object = self.order.remove(&number)?;
+        self.index.remove(&address(&object.data));
+        Some(object)
     }
-    if let Some((to_remove, val)) = out {
-        set.remove(&to_remove);
-        Some((to_remove.0, val))
-    } else {
-        None
+
+    /// The objects in the order they were added.
+    pub fn iter(self: &Self) -> btree_map::Values<'_, u64, ObjectRef> {
+        self.order.values()
     }
 }
 
@@ -0,0 +1,331 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use prelude::*;
+
+use data;
+use instance::FlopInstance;
+use runtime;
+
+const HELP: &str = "\
+    s, step         run to the next statement, entering calls
+    n, next         run to the next statement in this algorithm
+    f, finish       run until this algorithm returns or waits
+    e, event        run to the start of the next event
+    c, continue     run to the next breakpoint
+    b, break SPEC   stop at Type, Type.alg, Type:line or Type.alg:line
+    b, break        list breakpoints
+    d, delete N     remove breakpoint N
+    v, vars         show local variables
+    self            show the current object
+    q, queue        list pending events
+    bt              show the call stack
+    quit            stop the program";
+
+pub struct Breakpoint {
+    pub type_name: String,
+    pub algorithm_name: Option<String>,
+    pub line: Option<usize>,
+}
+
+impl Breakpoint {
+    /// Reads `Type`, `Type.alg`, `Type:line` or `Type.alg:line`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut parts = spec.splitn(2, ':');
+        let path = parts.next().unwrap_or("");
+        let line = match parts.next() {
+            Some(line) => Some(line.parse().ok()?),
+            None => None,
+        };
+
+        // type names can have a module in front, like `units.Soldier`, but
+        // only algorithm names start with a lowercase letter
+        let (type_name, algorithm_name) = match path.rfind('.') {
+            Some(dot) if !path[dot + 1..].starts_with(char::is_uppercase) => {
+                (&path[..dot], Some(path[dot + 1..].to_string()))
+            },
+            _ => (path, None),
+        };
+        let type_name = type_name.to_string();
+        if type_name.len() == 0 || algorithm_name.as_ref() == Some(&"".into()) {
+            return None;
+        }
+        Some(Breakpoint { type_name, algorithm_name, line })
+    }
+
+    // without a line, a breakpoint is hit on entering the algorithm
+    fn matches(
+        self: &Self,
+        frame: &runtime::Frame,
+        entering: bool,
+        new_line: bool,
+    ) -> bool {
+        if *frame.type_name != *self.type_name {
+            return false;
+        }
+        if let Some(ref algorithm_name) = self.algorithm_name {
+            if *frame.algorithm_name != **algorithm_name {
+                return false;
+            }
+        }
+        match self.line {
+            Some(line) => new_line && frame.line == line,
+            None => entering,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.type_name)?;
+        if let Some(ref algorithm_name) = self.algorithm_name {
+            write!(f, ".{}", algorithm_name)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+enum Mode {
+    Continue,
+    StepIn,
+    // the depth of the call stack when stepping started
+    StepOver(usize),
+    StepOut(usize),
+    NextEvent,
+}
+
+/// Hosts forward `before_statement` to this, and call `begin_event` before
+/// running each event.
+pub struct Debugger {
+    source_dir: PathBuf,
+    sources: Dict<Vec<String>>,
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    // call stack depth and line of the last statement, within this event
+    last: Option<(usize, usize)>,
+    event_started: bool,
+}
+
+impl Debugger {
+    /// Makes a debugger that stops at the very first statement, reading
+    /// source lines from the files in `source_dir`.
+    pub fn new(source_dir: PathBuf) -> Self {
+        Debugger {
+            source_dir,
+            sources: Dict::new(),
+            breakpoints: Vec::new(),
+            mode: Mode::StepIn,
+            last: None,
+            event_started: false,
+        }
+    }
+
+    /// Drops the source lines read so far, after the files have changed.
+    pub fn forget_sources(self: &mut Self) {
+        self.sources.clear();
+    }
+
+    /// Stops stepping, so that only breakpoints pause execution.
+    pub fn continue_to_breakpoint(self: &mut Self) {
+        self.mode = Mode::Continue;
+    }
+
+    pub fn add_breakpoint(self: &mut Self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    pub fn begin_event(self: &mut Self) {
+        self.event_started = true;
+        self.last = None;
+    }
+
+    /// Stops to take commands if a breakpoint was hit or a step finished,
+    /// returning false if the user asked to quit.
+    pub fn before_statement(
+        self: &mut Self,
+        game: &FlopInstance,
+        object: &data::Object,
+        locals: runtime::Locals,
+    ) -> bool {
+        let depth = game.call_stack.len();
+        let frame = match game.call_stack.last() {
+            Some(frame) => frame,
+            None => return true,
+        };
+        let entering = self.last.map_or(true, |(last, _)| last < depth);
+        let new_line = self.last != Some((depth, frame.line));
+        self.last = Some((depth, frame.line));
+        let event_started = self.event_started;
+        self.event_started = false;
+
+        let stepped = match self.mode {
+            Mode::Continue => false,
+            Mode::StepIn => true,
+            Mode::StepOver(start) => depth <= start,
+            Mode::StepOut(start) => depth < start || event_started,
+            Mode::NextEvent => event_started,
+        };
+        let hit = self.breakpoints
+            .iter()
+            .position(|breakpoint| {
+                breakpoint.matches(frame, entering, new_line)
+            });
+        if !stepped && hit.is_none() {
+            return true;
+        }
+
+        if let Some(index) = hit {
+            println!("breakpoint {}: {}", index, self.breakpoints[index]);
+        }
+        println!("{}", frame);
+        if let Some(text) = self.source_line(frame) {
+            println!("{:>4} | {}", frame.line, text);
+        }
+        self.prompt(game, object, locals, depth)
+    }
+
+    // returns false on `quit`
+    fn prompt(
+        self: &mut Self,
+        game: &FlopInstance,
+        object: &data::Object,
+        locals: runtime::Locals,
+        depth: usize,
+    ) -> bool {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(debug) ");
+            let _ = io::stdout().flush();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                // nobody is there to answer, so get out of the way
+                _ => {
+                    self.breakpoints.clear();
+                    self.mode = Mode::Continue;
+                    return true;
+                },
+            };
+
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let arg = words.next();
+            self.mode = match command {
+                "s" | "step" => Mode::StepIn,
+                "n" | "next" => Mode::StepOver(depth),
+                "f" | "finish" => Mode::StepOut(depth),
+                "e" | "event" => Mode::NextEvent,
+                "c" | "continue" => Mode::Continue,
+                "quit" => return false,
+                _ => {
+                    self.inspect(game, object, locals, command, arg);
+                    continue;
+                },
+            };
+            return true;
+        }
+    }
+
+    fn inspect(
+        self: &mut Self,
+        game: &FlopInstance,
+        object: &data::Object,
+        locals: runtime::Locals,
+        command: &str,
+        arg: Option<&str>,
+    ) {
+        let totem = &game.totem;
+        let symbols = &game.symbols;
+        match command {
+            "" => (),
+            "h" | "help" => println!("{}", HELP),
+            "b" | "break" => match arg {
+                Some(spec) => match Breakpoint::parse(spec) {
+                    Some(breakpoint) => {
+                        let index = self.breakpoints.len();
+                        println!("breakpoint {}: {}", index, breakpoint);
+                        self.breakpoints.push(breakpoint);
+                    },
+                    None => println!("invalid breakpoint '{}'", spec),
+                },
+                None => {
+                    for (index, breakpoint) in
+                        self.breakpoints.iter().enumerate()
+                    {
+                        println!("{}: {}", index, breakpoint);
+                    }
+                },
+            },
+            "d" | "delete" => {
+                match arg.and_then(|arg| arg.parse().ok()) {
+                    Some(index) if index < self.breakpoints.len() => {
+                        self.breakpoints.remove(index);
+                    },
+                    _ => println!("no such breakpoint"),
+                }
+            },
+            "v" | "vars" => {
+                let mut vars: Vec<_> = locals
+                    .iter()
+                    .map(|(name, value)| (symbols.name(name), value))
+                    .collect();
+                vars.sort_by_key(|&(name, _)| name);
+                for (name, value) in vars {
+                    let value = data::show_field(totem, symbols, value);
+                    println!("{} = {}", name, value);
+                }
+            },
+            "self" => {
+                let object = object.borrow(totem);
+                println!(
+                    "{} in state {}",
+                    symbols.name(object.type_name),
+                    symbols.name(object.state_name),
+                );
+                let fields = data::sorted_fields(symbols, &object.data);
+                for (name, value) in fields {
+                    let value = data::show_field(totem, symbols, value);
+                    println!("    {} = {}", name, value);
+                }
+            },
+            "q" | "queue" => {
+                println!("now {}", f64::from(game.now()));
+                for (time, _, event) in game.pending_events() {
+                    let object = event.object.borrow(totem);
+                    println!(
+                        "{:>10}  {}.{} @ pc {}",
+                        f64::from(time),
+                        symbols.name(object.type_name),
+                        symbols.name(event.action_name),
+                        event.pc,
+                    );
+                }
+            },
+            "bt" => {
+                for frame in game.call_stack.iter().rev() {
+                    println!("    {}", frame);
+                }
+            },
+            _ => println!("unknown command '{}', try help", command),
+        }
+    }
+
+    fn source_line(self: &mut Self, frame: &runtime::Frame) -> Option<&str> {
+        let source_name: &str = &frame.source_name;
+        if !self.sources.contains_key(source_name) {
+            let path = self.source_dir.join(source_name);
+            let lines = fs::read_to_string(path)
+                .map(|text| text.lines().map(String::from).collect())
+                .unwrap_or(Vec::new());
+            self.sources.insert(source_name.into(), lines);
+        }
+        let line = frame.line.checked_sub(1)?;
+        self.sources[source_name].get(line).map(|line| line.trim())
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+
+use runtime;
+use symbol::{Interner, Symbol};
+
+/// A failure while running flop code.
+///
+/// These are returned rather than panicking, so that the host can log the
+/// failure and carry on with the rest of the simulation.
+#[derive(Clone, Debug)]
+pub enum FlopError {
+    TypeMismatch {
+        expected: &'static str,
+        found: String,
+    },
+    // a type mismatch on data, whose variant gets named by `named`
+    DataMismatch {
+        expected: &'static str,
+        variant: Symbol,
+    },
+    UnknownVariable(String),
+    UnknownType(String),
+    UnknownInterface {
+        type_name: String,
+        interface_name: String,
+    },
+    UnknownMethod {
+        type_name: String,
+        interface_name: String,
+        method_name: String,
+    },
+    UnknownAlgorithm {
+        type_name: String,
+        algorithm_name: String,
+    },
+    UnknownSetMethod(String),
+    MissingField {
+        variant: String,
+        field: String,
+    },
+    WrongArgCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    WrongResultCount {
+        expected: usize,
+        found: usize,
+    },
+    EmptySet,
+    InvalidTime(f64),
+    ExitWithoutState {
+        type_name: String,
+        algorithm_name: String,
+    },
+    NoExtern(String),
+    Extern(String),
+    // another error, along with the flop call stack where it happened, most
+    // recent call first
+    Traced(Box<FlopError>, Vec<runtime::Frame>),
+}
+
+impl FlopError {
+    /// Attaches a call stack, unless one is already attached.
+    pub fn traced(self: Self, stack: &[runtime::Frame]) -> Self {
+        match self {
+            FlopError::Traced(..) => self,
+            cause => {
+                let trace = stack.iter().rev().cloned().collect();
+                FlopError::Traced(Box::new(cause), trace)
+            },
+        }
+    }
+
+    /// Fills in the names of any symbols, which only the interner knows.
+    pub fn named(self: Self, symbols: &Interner) -> Self {
+        match self {
+            FlopError::DataMismatch { expected, variant } => {
+                let found = format!("data '{}'", symbols.name(variant));
+                FlopError::TypeMismatch { expected, found }
+            },
+            FlopError::Traced(cause, trace) => {
+                FlopError::Traced(Box::new(cause.named(symbols)), trace)
+            },
+            other => other,
+        }
+    }
+
+    /// The underlying error, without any trace.
+    pub fn cause(self: &Self) -> &FlopError {
+        match *self {
+            FlopError::Traced(ref cause, _) => cause,
+            _ => self,
+        }
+    }
+
+    pub fn trace(self: &Self) -> &[runtime::Frame] {
+        match *self {
+            FlopError::Traced(_, ref trace) => trace,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for FlopError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FlopError::*;
+        match *self {
+            TypeMismatch { expected, ref found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            },
+            DataMismatch { expected, .. } => {
+                write!(f, "expected {}, found data", expected)
+            },
+            UnknownVariable(ref name) => {
+                write!(f, "unknown variable '{}'", name)
+            },
+            UnknownType(ref name) => write!(f, "unknown type '{}'", name),
+            UnknownInterface { ref type_name, ref interface_name } => {
+                write!(
+                    f,
+                    "type '{}' has no interface '{}'",
+                    type_name,
+                    interface_name,
+                )
+            },
+            UnknownMethod {
+                ref type_name,
+                ref interface_name,
+                ref method_name,
+            } => {
+                write!(
+                    f,
+                    "interface '{}.{}' has no method '{}'",
+                    type_name,
+                    interface_name,
+                    method_name,
+                )
+            },
+            UnknownAlgorithm { ref type_name, ref algorithm_name } => {
+                write!(
+                    f,
+                    "type '{}' has no algorithm '{}'",
+                    type_name,
+                    algorithm_name,
+                )
+            },
+            UnknownSetMethod(ref name) => {
+                write!(f, "sets have no method '{}'", name)
+            },
+            MissingField { ref variant, ref field } => {
+                write!(f, "data '{}' has no field '{}'", variant, field)
+            },
+            WrongArgCount { ref name, expected, found } => {
+                write!(
+                    f,
+                    "{} expects {} argument(s), got {}",
+                    name,
+                    expected,
+                    found,
+                )
+            },
+            WrongResultCount { expected, found } => {
+                write!(f, "expected {} result(s), got {}", expected, found)
+            },
+            EmptySet => write!(f, "cannot take from an empty set"),
+            InvalidTime(time) => write!(f, "invalid wait time {}", time),
+            ExitWithoutState { ref type_name, ref algorithm_name } => {
+                write!(
+                    f,
+                    "{}.{} exited without setting self.data",
+                    type_name,
+                    algorithm_name,
+                )
+            },
+            NoExtern(ref name) => {
+                write!(f, "no extern function 'game.{}'", name)
+            },
+            Extern(ref message) => write!(f, "extern error: {}", message),
+            Traced(ref cause, ref trace) => {
+                write!(f, "{}", cause)?;
+                for frame in trace {
+                    write!(f, "\n    at {}", frame)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
@@ -1,35 +1,103 @@
+use std::collections::{BTreeMap, HashMap};
+
 use sulphate_lib::event_queue;
 
 use prelude::*;
 
 use data;
+use error::FlopError;
+use instance::FlopParts;
 use runtime;
+use symbol::Symbol;
 
 
 pub type EventQueue = event_queue::EventQueue<Event>;
 
+/// A copy of every pending event, by object address, since the queue itself
+/// can't be walked, along with when each is due.
+#[derive(Default)]
+pub struct Waiting {
+    // each event with its time and queue id
+    events: HashMap<usize, ((Time, usize), Event)>,
+    // the address behind each time and queue id, soonest first
+    due: BTreeMap<(Time, usize), usize>,
+}
+
+impl Waiting {
+    pub fn new() -> Self {
+        Waiting::default()
+    }
+
+    pub fn insert(
+        self: &mut Self,
+        address: usize,
+        time: Time,
+        queue_id: usize,
+        event: Event,
+    ) {
+        self.remove(&address);
+        self.due.insert((time, queue_id), address);
+        self.events.insert(address, ((time, queue_id), event));
+    }
+
+    pub fn remove(self: &mut Self, address: &usize) -> Option<Event> {
+        let (key, event) = self.events.remove(address)?;
+        self.due.remove(&key);
+        Some(event)
+    }
+
+    pub fn get(self: &Self, address: &usize) -> Option<&Event> {
+        self.events.get(address).map(|&(_, ref event)| event)
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.events.clear();
+        self.due.clear();
+    }
+
+    /// Every event with its time and queue id, in the order they will run.
+    pub fn iter(self: &Self) -> impl Iterator<Item=(Time, usize, &Event)> {
+        self.due.iter().map(move |(&(time, queue_id), address)| {
+            (time, queue_id, &self.events[address].1)
+        })
+    }
+
+    /// The time of the soonest event.
+    pub fn next_time(self: &Self) -> Option<Time> {
+        self.due.keys().next().map(|&(time, _)| time)
+    }
+}
+
 pub struct EventHandle(pub Time, pub usize);
 
+#[derive(Clone)]
 pub struct Event {
     pub object: data::Object,
 
-    pub action_name: String,
+    pub action_name: Symbol,
     pub pc: usize,
 }
 
 impl Event {
-    pub fn invoke<G: Flop>(self: Self, game: &mut G) {
+    pub fn invoke<G: Flop>(self: Self, game: &mut G) -> Result<(), FlopError> {
+        // the queue has let go of this event, so nothing else should refer
+        // to it, even if the object fails to resume
+        game.waiting().remove(&data::address(&self.object));
+        self.object.borrow_mut(game.totem()).event = None;
         runtime::resume_algorithm(
             game,
             self.object,
             self.action_name,
             self.pc,
-        );
+        )
     }
 }
 
 impl<G: Flop> event_queue::GeneralEvent<G> for Event {
     fn invoke(self: Self, game: &mut G) {
-        Event::invoke(self, game);
+        let object = Strong::clone(&self.object);
+        if let Err(error) = Event::invoke(self, game) {
+            game.event_error(object, error);
+        }
     }
 }
@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use prelude::*;
+
+use ast;
+use item;
+
+pub enum ImportError {
+    Unknown(String),
+    Duplicate(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::Unknown(ref name) => {
+                write!(f, "there is no class or role '{}' to use", name)
+            },
+            ImportError::Duplicate(ref name) => {
+                write!(f, "'{}' is already imported", name)
+            },
+        }
+    }
+}
+
+/// The classes and roles that short names can refer to in one file.
+pub struct Scope<'a> {
+    // empty for classes in no module
+    module: &'a str,
+    imports: Dict<String>,
+    classes: &'a HashSet<String>,
+    // full names of the roles declared outside any class
+    roles: &'a HashSet<String>,
+}
+
+impl<'a> Scope<'a> {
+    /// The scope of a file in `module`, which is empty for none.
+    pub fn new(
+        module: &'a str,
+        classes: &'a HashSet<String>,
+        roles: &'a HashSet<String>,
+    ) -> Self {
+        let imports = Dict::new();
+        Scope { module, imports, classes, roles }
+    }
+
+    pub fn import(
+        self: &mut Self,
+        import: &item::Import,
+    ) -> Result<(), ImportError> {
+        let full_name = import.path.join(".");
+        let known = self.classes.contains(&full_name)
+            || self.roles.contains(&full_name);
+        if !known {
+            return Err(ImportError::Unknown(full_name));
+        }
+        let short_name = import.path[import.path.len() - 1].clone();
+        if self.imports.contains_key(&short_name) {
+            return Err(ImportError::Duplicate(short_name));
+        }
+        self.imports.insert(short_name, full_name);
+        Ok(())
+    }
+
+    /// The full name of the class that `name` refers to here: one imported
+    /// with `use`, one in the same module, or one in no module.
+    pub fn resolve(self: &Self, name: &str) -> Option<String> {
+        self.resolve_in(self.classes, name)
+    }
+
+    /// The full name of the role outside any class that `name` refers to
+    /// here.
+    pub fn resolve_role(self: &Self, name: &str) -> Option<String> {
+        self.resolve_in(self.roles, name)
+    }
+
+    fn resolve_in(
+        self: &Self,
+        names: &HashSet<String>,
+        name: &str,
+    ) -> Option<String> {
+        // a name with a module in front is already full
+        if name.contains('.') {
+            return Some(name.into()).filter(|name| names.contains(name));
+        }
+        if let Some(full_name) = self.imports.get(name) {
+            if names.contains(full_name) {
+                return Some(full_name.clone());
+            }
+        }
+        if self.module.len() > 0 {
+            let full_name = format!("{}.{}", self.module, name);
+            if names.contains(&full_name) {
+                return Some(full_name);
+            }
+        }
+        if names.contains(name) {
+            return Some(name.into());
+        }
+        None
+    }
+
+    /// Rewrites every class name in a class's items, or a file's, to its
+    /// full name.
+    ///
+    /// Names that don't resolve are left for the type checker to report.
+    pub fn qualify_items(self: &Self, items: &mut Vec<(String, item::Item)>) {
+        // roles declared in the class shadow the ones outside it
+        let own_roles: HashSet<String> = items
+            .iter()
+            .filter_map(|&(ref name, ref item)| match *item {
+                item::Item::Role(_) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        for &mut (_, ref mut item) in items.iter_mut() {
+            match *item {
+                item::Item::Function(ref mut alg)
+                | item::Item::Constructor(ref mut alg) => {
+                    self.algorithm(alg);
+                },
+                item::Item::Interface {
+                    ref mut type_name,
+                    ref mut role_name,
+                    ..
+                } => match *type_name {
+                    Some(ref mut type_name) => self.qualify(type_name),
+                    None if !own_roles.contains(role_name) => {
+                        if let Some(full_name) = self.resolve_role(role_name) {
+                            *role_name = full_name;
+                        }
+                    },
+                    None => (),
+                },
+                item::Item::Role(ref mut role) => self.qualify_role(role),
+                item::Item::Data(ref mut decl) => {
+                    for variant in &mut decl.variants {
+                        self.variant(variant);
+                    }
+                },
+                item::Item::State { ref mut variant, .. } => {
+                    self.variant(variant);
+                },
+            }
+        }
+    }
+
+    pub fn qualify_role(self: &Self, role: &mut item::Role) {
+        for term in role.values_mut() {
+            match *term {
+                item::RoleTerm::Constructor(ref mut params)
+                | item::RoleTerm::Function(ref mut params) => {
+                    for param in params {
+                        self.field_type(param);
+                    }
+                },
+            }
+        }
+    }
+
+    fn qualify(self: &Self, name: &mut String) {
+        if let Some(full_name) = self.resolve(name) {
+            *name = full_name;
+        }
+    }
+
+    fn field_type(self: &Self, field: &mut item::FieldType) {
+        match *field {
+            item::FieldType::Num => (),
+            item::FieldType::VRef { ref mut type_name, .. } => {
+                self.qualify(type_name);
+            },
+            item::FieldType::TRef(ref mut name) => self.qualify(name),
+        }
+    }
+
+    fn variant(self: &Self, variant: &mut item::Variant) {
+        for &mut (_, ref mut field) in &mut variant.fields {
+            self.field_type(field);
+        }
+    }
+
+    fn algorithm(self: &Self, alg: &mut ast::Algorithm) {
+        for param in &mut alg.param_types {
+            if let Some(ref mut param) = *param {
+                self.field_type(param);
+            }
+        }
+        if let Some(ref mut return_types) = alg.return_types {
+            for ty in return_types {
+                self.field_type(ty);
+            }
+        }
+        self.block(&mut alg.steps);
+    }
+
+    fn block(self: &Self, block: &mut ast::Block) {
+        for &mut (_, ref mut statement) in block.iter_mut() {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(self: &Self, statement: &mut ast::Statement) {
+        use ast::Statement::*;
+        match *statement {
+            Bang => (),
+            Evaluate { ref mut expressions, .. } => {
+                for expression in expressions {
+                    self.expression(expression);
+                }
+            },
+            State(ref mut state) => self.expression(state),
+            WhileLoop { ref mut condition, ref mut block } => {
+                self.expression(condition);
+                self.block(block);
+            },
+            Branch { ref mut if_branches, ref mut else_branch } => {
+                for &mut (ref mut condition, ref mut block) in if_branches {
+                    self.expression(condition);
+                    self.block(block);
+                }
+                self.block(else_branch);
+            },
+            Match { ref mut data, ref mut arms, ref mut def } => {
+                self.expression(data);
+                for &mut (_, _, ref mut block) in arms {
+                    self.block(block);
+                }
+                if let Some(ref mut def) = *def {
+                    self.block(def);
+                }
+            },
+        }
+    }
+
+    fn expression(self: &Self, expression: &mut ast::Expression) {
+        use ast::Expression::*;
+        match *expression {
+            Var(_) | SelfObject | SelfData | Const(_) | Bool(_) => (),
+            Method { ref mut names, ref mut args } => {
+                // `Type.ctor(..)` and `Type.Interface.ctor(..)`, where the
+                // type can have its module in front, as in
+                // `units.Soldier.make(..)`
+                let is_type = |name: &String| {
+                    name.chars().next().map_or(false, char::is_uppercase)
+                };
+                if let Some(first) = names.iter().position(is_type) {
+                    if first > 0 && first + 1 < names.len() {
+                        let module: Vec<String> =
+                            names.drain(..first).collect();
+                        names[0] = format!("{}.{}", module.join("."), names[0]);
+                    }
+                }
+                let is_builtin = names.len() == 2
+                    && names[0] == "Set"
+                    && names[1] == "new";
+                if names.len() > 1 && is_type(&names[0]) && !is_builtin {
+                    self.qualify(&mut names[0]);
+                }
+                for arg in args {
+                    self.expression(arg);
+                }
+            },
+            Data { ref mut fields, .. } => {
+                for &mut (_, ref mut value) in fields {
+                    self.expression(value);
+                }
+            },
+            Comparison(ref mut x, ref mut ops) => {
+                self.expression(x);
+                for &mut (_, ref mut y) in ops {
+                    self.expression(y);
+                }
+            },
+            Not(ref mut x) | Neg(ref mut x) => self.expression(x),
+            And(ref mut x, ref mut y)
+            | Or(ref mut x, ref mut y)
+            | Add(ref mut x, ref mut y)
+            | Sub(ref mut x, ref mut y)
+            | Mul(ref mut x, ref mut y)
+            | Div(ref mut x, ref mut y)
+            | Mod(ref mut x, ref mut y)
+            | Pow(ref mut x, ref mut y) => {
+                self.expression(x);
+                self.expression(y);
+            },
+        }
+    }
+}
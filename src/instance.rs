@@ -1,9 +1,13 @@
 use prelude::*;
 
 use data;
+use error::FlopError;
 use event;
 use item;
+use load_types;
 use runtime;
+use symbol::{Interner, SymbolMap};
+use trace;
 
 pub use sulphate_lib::event_queue::Simulation;
 
@@ -11,10 +15,45 @@ pub struct FlopInstance {
     pub totem: Totem,
     pub event_queue: event::EventQueue,
     // object types? that's stupid
-    pub types: Dict<item::ObjectType>,
+    // only replaced wholesale, by a reload; see `types` for reading them
+    pub(crate) types: SymbolMap<item::ObjectType>,
+    // roles live in their own namespace, separate from types
+    pub roles: Dict<item::Role>,
+    // the names behind every symbol in `types` and in objects
+    pub symbols: Interner,
+    pub call_stack: Vec<runtime::Frame>,
+    pub waiting: event::Waiting,
+    // records what happens, if set
+    pub tracer: Option<trace::Tracer>,
 }
 
 impl FlopInstance {
+    pub fn new(
+        totem: Totem,
+        event_queue: event::EventQueue,
+        content: load_types::Content,
+    ) -> Self {
+        let load_types::Content { types, roles, symbols } = content;
+        let call_stack = Vec::new();
+        let waiting = event::Waiting::new();
+        let tracer = None;
+        FlopInstance {
+            totem,
+            event_queue,
+            types,
+            roles,
+            symbols,
+            call_stack,
+            waiting,
+            tracer,
+        }
+    }
+
+    /// The loaded types, which can only be changed by loading new content.
+    pub fn types(self: &Self) -> &SymbolMap<item::ObjectType> {
+        &self.types
+    }
+
     pub fn invoke_next(self: &mut Self) {
         Simulation::invoke_next(self);
     }
@@ -24,19 +63,95 @@ impl FlopInstance {
 
         root_type: String,
         alg_name: String,
-    ) {
-        let _root = runtime::execute_ctor_concrete(
-            game,
-
-            root_type,
-            alg_name,
-            Vec::new(),
-        );
+    ) -> Result<(), FlopError> {
+        let _root = FlopInstance::start(game, root_type, alg_name, Vec::new())?;
 
         while !game.event_queue().is_empty() {
             Simulation::invoke_next(game);
         }
         println!("Nothing happened.");
+        Ok(())
+    }
+
+    /// Constructs the root object without running any events.
+    ///
+    /// Keep the result alive if anything should be able to reach the root
+    /// after it stops waiting.
+    pub fn start<G: Flop>(
+        game: &mut G,
+
+        root_type: String,
+        alg_name: String,
+        args: Vec<data::Field>,
+    ) -> Result<data::Object, FlopError> {
+        let (root_type, alg_name) = {
+            let instance: &mut FlopInstance = game.as_mut();
+            let symbols = &mut instance.symbols;
+            (symbols.intern(&root_type), symbols.intern(&alg_name))
+        };
+        runtime::execute_ctor_concrete(game, root_type, alg_name, args)
+    }
+
+    /// Runs up to `n` events, returning how many actually ran.
+    pub fn step<G: Flop>(game: &mut G, n: usize) -> usize {
+        let mut count = 0;
+        while count < n && !game.event_queue().is_empty() {
+            Simulation::invoke_next(game);
+            count += 1;
+        }
+        count
+    }
+
+    /// Runs every event due at or before `time`, returning how many ran.
+    ///
+    /// The queue's clock only moves when an event runs, so afterwards `now`
+    /// is the time of the last event rather than `time` itself.
+    pub fn run_until<G: Flop>(game: &mut G, time: Time) -> usize {
+        let mut count = 0;
+        loop {
+            let instance: &mut FlopInstance = game.as_mut();
+            match instance.next_event_time() {
+                Some(next) if next <= time => (),
+                _ => break,
+            }
+            Simulation::invoke_next(game);
+            count += 1;
+        }
+        count
+    }
+
+    /// The time of the event that ran most recently.
+    pub fn now(self: &Self) -> Time {
+        self.event_queue.now()
+    }
+
+    /// Every pending event in the order it will run, with its time and
+    /// queue id.
+    pub fn pending_events(self: &Self) -> Vec<(Time, usize, &event::Event)> {
+        self.waiting.iter().collect()
+    }
+
+    // replaces the event queue with one holding `events`, which keep the
+    // order of their old queue ids among events due at the same time
+    pub(crate) fn requeue(
+        self: &mut Self,
+        now: Time,
+        mut events: Vec<(Time, usize, event::Event)>,
+    ) {
+        self.event_queue = event::EventQueue::new(now);
+        self.waiting.clear();
+        events.sort_by_key(|&(time, queue_id, _)| (time, queue_id));
+        for (time, _, event) in events {
+            let object = Strong::clone(&event.object);
+            let id = self.event_queue.enqueue_absolute(event.clone(), time);
+            self.waiting.insert(data::address(&object), time, id, event);
+            let object = object.borrow_mut(&mut self.totem);
+            object.event = Some(event::EventHandle(time, id));
+        }
+    }
+
+    pub fn next_event_time(self: &Self) -> Option<Time> {
+        self.waiting.next_time()
     }
 }
 
@@ -45,7 +160,41 @@ pub trait Flop: AsMut<FlopInstance> + AsMut<event::EventQueue> {
         self: &mut Self,
         func_name: &String,
         args: Vec<data::Field>,
-    ) -> Vec<data::Field>;
+    ) -> Result<Vec<data::Field>, FlopError>;
+
+    /// Called when an algorithm resumed by the event queue fails.
+    ///
+    /// By this point the object has no pending event, so it will sit
+    /// untouched unless other objects call into it.
+    fn event_error(self: &mut Self, object: data::Object, error: FlopError) {
+        drop(object);
+        eprintln!("Error: {}", error);
+    }
+
+    /// Called by `print`, `trace()` and `BANG;`, with the object and
+    /// algorithm that printed.
+    fn output(
+        self: &mut Self,
+        _object: &data::Object,
+        _algorithm_name: &str,
+        output: runtime::Output,
+    ) {
+        let instance: &mut FlopInstance = self.as_mut();
+        let text = output.show(&instance.totem, &instance.symbols);
+        match output {
+            runtime::Output::Trace(_) => println!("{}", text),
+            _ => println!("Debug: {}", text),
+        }
+    }
+
+    /// Called before every statement, with the top of `call_stack` saying
+    /// where execution is, for debuggers and the like.
+    fn before_statement(
+        self: &mut Self,
+        _object: &data::Object,
+        _locals: runtime::Locals,
+    ) {
+    }
 }
 
 impl AsMut<FlopInstance> for FlopInstance {
@@ -65,41 +214,217 @@ impl AsMut<event::EventQueue> for FlopInstance {
 impl Flop for FlopInstance {
     fn extern_call(
         self: &mut Self,
-        _func_name: &String,
+        func_name: &String,
         _args: Vec<data::Field>,
-    ) -> Vec<data::Field> {
-        panic!("Tried to call rust code from default Flop environment");
+    ) -> Result<Vec<data::Field>, FlopError> {
+        Err(FlopError::NoExtern(func_name.clone()))
+    }
+}
+
+// a host for tests that logs each output as a "Debug: " line
+#[cfg(test)]
+pub(crate) mod test_host {
+    use std::cell::RefCell;
+    use std::io;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    use prelude::*;
+
+    use data;
+    use error::FlopError;
+    use event::EventQueue;
+    use load_types;
+    use runtime;
+    use super::FlopInstance;
+
+    // a log that a tracer can write lines to as well
+    #[derive(Clone, Default)]
+    pub struct Log(Rc<RefCell<Vec<u8>>>);
+
+    impl Log {
+        pub fn text(self: &Self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for Log {
+        fn write(self: &mut Self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(self: &mut Self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub struct Host {
+        pub instance: FlopInstance,
+        pub log: Log,
+    }
+
+    impl Host {
+        // starting at time 0
+        pub fn new(content: load_types::Content) -> Self {
+            let totem = unsafe { Totem::new() };
+            let event_queue = EventQueue::new(Time::try_from(0.0).unwrap());
+            let instance = FlopInstance::new(totem, event_queue, content);
+            Host { instance, log: Log::default() }
+        }
+
+        // what was printed, without the prefix
+        pub fn printed(self: &Self) -> Vec<String> {
+            self.log.text()
+                .lines()
+                .filter(|line| line.starts_with("Debug: "))
+                .map(|line| line["Debug: ".len()..].into())
+                .collect()
+        }
+    }
+
+    impl AsMut<FlopInstance> for Host {
+        fn as_mut(self: &mut Self) -> &mut FlopInstance {
+            &mut self.instance
+        }
+    }
+
+    impl AsMut<EventQueue> for Host {
+        fn as_mut(self: &mut Self) -> &mut EventQueue {
+            &mut self.instance.event_queue
+        }
+    }
+
+    impl Flop for Host {
+        fn extern_call(
+            self: &mut Self,
+            func_name: &String,
+            _args: Vec<data::Field>,
+        ) -> Result<Vec<data::Field>, FlopError> {
+            Err(FlopError::NoExtern(func_name.clone()))
+        }
+
+        fn output(
+            self: &mut Self,
+            _object: &data::Object,
+            _algorithm_name: &str,
+            output: runtime::Output,
+        ) {
+            let instance = &self.instance;
+            let text = output.show(&instance.totem, &instance.symbols);
+            writeln!(self.log, "Debug: {}", text).unwrap();
+        }
     }
 }
 
 pub(crate) trait FlopParts {
     fn parts(self: &mut Self) -> (
         &mut Totem,
-        &mut Dict<item::ObjectType>,
         &mut event::EventQueue,
+        &mut event::Waiting,
     );
     fn totem(self: &mut Self) -> &mut Totem;
-    fn types(self: &mut Self) -> &mut Dict<item::ObjectType>;
     fn event_queue(self: &mut Self) -> &mut event::EventQueue;
+    fn call_stack(self: &mut Self) -> &mut Vec<runtime::Frame>;
+    fn waiting(self: &mut Self) -> &mut event::Waiting;
 }
 
 impl<G: Flop> FlopParts for G {
     fn parts(self: &mut Self) -> (
         &mut Totem,
-        &mut Dict<item::ObjectType>,
         &mut event::EventQueue,
+        &mut event::Waiting,
     ) {
         let instance: &mut FlopInstance = self.as_mut();
-        (&mut instance.totem, &mut instance.types, &mut instance.event_queue)
+        (
+            &mut instance.totem,
+            &mut instance.event_queue,
+            &mut instance.waiting,
+        )
     }
 
     fn totem(self: &mut Self) -> &mut Totem {
         self.parts().0
     }
-    fn types(self: &mut Self) -> &mut Dict<item::ObjectType> {
+    fn event_queue(self: &mut Self) -> &mut event::EventQueue {
         self.parts().1
     }
-    fn event_queue(self: &mut Self) -> &mut event::EventQueue {
+    fn call_stack(self: &mut Self) -> &mut Vec<runtime::Frame> {
+        let instance: &mut FlopInstance = self.as_mut();
+        &mut instance.call_stack
+    }
+    fn waiting(self: &mut Self) -> &mut event::Waiting {
         self.parts().2
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use data::Field;
+    use instance::FlopInstance;
+    use instance::test_host::Host;
+    use load_types;
+    use trace::Tracer;
+
+    // what the tree-walking interpreter that came before the bytecode did
+    // with the same scripts, written with `while True {} {` back then
+    const DATA: &[&str] = &[
+        r#"{"t":0,"kind":"state","object":0,"type":"Root","from":"EMPTY","to":"Null","data":{}}"#,
+        r#"{"t":0,"kind":"state","object":1,"type":"Looper","from":"EMPTY","to":"Wait","data":{}}"#,
+        r#"Debug: BANG"#,
+        r#"{"t":1,"kind":"state","object":1,"type":"Looper","from":"Wait","to":"Wait","data":{}}"#,
+        r#"Debug: BANG"#,
+        r#"{"t":2,"kind":"state","object":1,"type":"Looper","from":"Wait","to":"Wait","data":{}}"#,
+        r#"Debug: BANG"#,
+        r#"{"t":3,"kind":"state","object":1,"type":"Looper","from":"Wait","to":"Wait","data":{}}"#,
+        r#"Debug: BANG"#,
+        r#"{"t":4,"kind":"state","object":1,"type":"Looper","from":"Wait","to":"Wait","data":{}}"#,
+    ];
+    // the last few states of three walkers after 80 events
+    const MANY_OBJECTS: &[&str] = &[
+        r#"{"t":39,"kind":"state","object":2,"type":"Walker","from":"Walking","to":"Walking","data":{"leader":{"object":1,"type":"Walker"},"speed":0.0001336571821429856,"x":11.187866342674937}}"#,
+        r#"{"t":39,"kind":"state","object":3,"type":"Walker","from":"Walking","to":"Walking","data":{"leader":{"object":2,"type":"Walker"},"speed":0.4444444444444444,"x":21.941975308641986}}"#,
+        r#"{"t":40,"kind":"state","object":2,"type":"Walker","from":"Walking","to":"Walking","data":{"leader":{"object":1,"type":"Walker"},"speed":0.00008910478809532372,"x":11.18791089514838}}"#,
+        r#"{"t":40,"kind":"state","object":3,"type":"Walker","from":"Walking","to":"Walking","data":{"leader":{"object":2,"type":"Walker"},"speed":0.2962962962962963,"x":22.091001371742124}}"#,
+    ];
+
+    // the state changes and output of running a directory under the crate
+    fn run(dir: &str, root: &str, args: Vec<Field>, events: usize) -> String {
+        let dir = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), dir);
+        let content = load_types::get_types(&dir).ok().unwrap();
+        let mut host = Host::new(content);
+        host.instance.tracer = Some(Tracer::new(host.log.clone()));
+
+        let _root = FlopInstance::start(
+            &mut host,
+            root.into(),
+            "init".into(),
+            args,
+        ).ok().unwrap();
+        assert_eq!(FlopInstance::step(&mut host, events), events);
+        host.log.text()
+    }
+
+    fn states_and_output(log: &str) -> Vec<&str> {
+        log.lines()
+            .filter(|line| {
+                line.starts_with("Debug: ")
+                    || line.contains(r#""kind":"state""#)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn data_matches_old_interpreter() {
+        let log = run("data", "Root", Vec::new(), 4);
+        assert_eq!(states_and_output(&log), DATA);
+    }
+
+    #[test]
+    fn many_objects_matches_old_interpreter() {
+        let args = vec![Field::Num(3.0)];
+        let log = run("examples/many_objects", "Swarm", args, 80);
+        let states = states_and_output(&log);
+        assert_eq!(states.len(), 84);
+        assert_eq!(states[80..], *MANY_OBJECTS);
+    }
+}
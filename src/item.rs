@@ -1,7 +1,13 @@
+use std::rc::Rc;
+
 use prelude::*;
 
 use ast;
+use error::FlopError;
 use runtime;
+use symbol::{Interner, Symbol, SymbolMap};
+use trivia;
+use typeck;
 
 //
 // AST
@@ -11,13 +17,44 @@ pub enum Item {
     Function(ast::Algorithm),
     Constructor(ast::Algorithm),
     Interface {
+        offset: usize,
         type_name: Option<String>,
         role_name: String,
         implementors: Dict<String>,
     },
     Role(Role),
+    Data(DataDecl),
+    State {
+        offset: usize,
+        // wait-bound states may only be assigned right before a wait
+        wait_bound: bool,
+        variant: Variant,
+    },
+}
+
+/// Everything declared in one source file.
+pub struct File {
+    pub imports: Vec<Import>,
+    // with the byte offset of each declaration, for error messages
+    pub items: Vec<(usize, String, FileItem)>,
+    // filled in after parsing, see `load_types::parse_file`
+    pub comments: Vec<trivia::Comment>,
+}
+
+pub enum FileItem {
+    Class(Vec<(String, Item)>),
+    Role(Role),
+    Data(DataDecl),
+}
+
+// `use units.Soldier;`, so that `Soldier` means `units.Soldier`
+pub struct Import {
+    // byte offset of the declaration, for error messages
+    pub offset: usize,
+    pub path: Vec<String>,
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub enum FieldType {
     Num,
     VRef { type_name: String, interface_name: String },
@@ -26,75 +63,222 @@ pub enum FieldType {
 
 pub type Role = Dict<RoleTerm>;
 
+pub struct DataDecl {
+    // byte offset of the declaration, for error messages
+    pub offset: usize,
+    pub variants: Vec<Variant>,
+}
+
+pub struct Variant {
+    pub name: String,
+    pub fields: Vec<(String, FieldType)>,
+}
+
 pub enum RoleTerm {
     Constructor(Vec<FieldType>),
     Function(Vec<FieldType>),
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AlgorithmKind {
+    Constructor,
+    Function,
+}
+
 //
 // runtime
 //
 
 pub struct Interface {
     pub implementors: Dict<String>,
+    // the type that declared the role, if not this one
+    pub role_type: Option<String>,
+    pub role_name: String,
 }
 
 pub struct ObjectType {
-    interfaces: Dict<Interface>,
-    algorithms: Dict<runtime::Algorithm>,
+    pub(crate) interfaces: Dict<Interface>,
+    // shared with every frame running them, so that replacing a type never
+    // pulls code out from under a running algorithm
+    pub(crate) algorithms: Vec<Rc<runtime::Algorithm>>,
+    pub(crate) algorithm_ids: SymbolMap<usize>,
+    // algorithm ids by interface and method, for dispatch on vrefs
+    pub(crate) tables: SymbolMap<SymbolMap<usize>>,
+    pub(crate) kinds: Dict<AlgorithmKind>,
+    pub(crate) roles: Dict<Role>,
 }
 
-pub fn collect(items: Vec<(String, Item)>) -> ObjectType {
+impl ObjectType {
+    pub(crate) fn algorithm(
+        self: &Self,
+        name: Symbol,
+    ) -> Option<&runtime::Algorithm> {
+        self.algorithm_ids.get(&name).map(|&id| &*self.algorithms[id])
+    }
+}
+
+pub fn collect(
+    items: Vec<(String, Item)>,
+    type_name: &Rc<str>,
+    source_name: &Rc<str>,
+    lines: &ast::LineIndex,
+    symbols: &mut Interner,
+) -> Result<ObjectType, typeck::TypeError> {
     let mut interfaces = Dict::new();
-    let mut algorithms = Dict::new();
+    let mut algorithms = Vec::new();
+    let mut tables = SymbolMap::new();
+    let mut kinds = Dict::new();
     let mut roles = Dict::new();
 
+    // number the algorithms first, so that calls on `self` can use ids;
+    // these are their indices in `algorithms`
+    let mut algorithm_ids = SymbolMap::new();
+    let mut next_id = 0;
+    for &(ref name, ref item) in &items {
+        match *item {
+            Item::Function(_) | Item::Constructor(_) => {
+                algorithm_ids.insert(symbols.intern(name), next_id);
+                next_id += 1;
+            },
+            _ => (),
+        }
+    }
+    let context = ast::Context {
+        type_name,
+        source_name,
+        lines,
+        algorithms: &algorithm_ids,
+    };
+
     for (name, item) in items {
         match item {
-            Item::Interface { type_name, role_name, implementors } => {
-                // these will be useful for static analysis one day
-                drop(type_name);
-                drop(role_name);
-                interfaces.insert(name, Interface { implementors });
+            Item::Interface { type_name, role_name, implementors, .. } => {
+                let role_type = type_name;
+                let mut table = SymbolMap::new();
+                for (method, algorithm) in &implementors {
+                    let algorithm = symbols.intern(algorithm);
+                    if let Some(&id) = algorithm_ids.get(&algorithm) {
+                        table.insert(symbols.intern(method), id);
+                    }
+                }
+                tables.insert(symbols.intern(&name), table);
+                let interface = Interface {
+                    implementors,
+                    role_type,
+                    role_name,
+                };
+                interfaces.insert(name, interface);
             },
-            Item::Function(alg) | Item::Constructor(alg) => {
-                let alg = ast::convert_algorithm(alg);
-                algorithms.insert(name, alg);
+            Item::Function(alg) => {
+                let alg = ast::convert_algorithm(alg, &name, &context, symbols)
+                    .map_err(|err| compile_error(type_name, &name, err))?;
+                kinds.insert(name, AlgorithmKind::Function);
+                algorithms.push(Rc::new(alg));
+            },
+            Item::Constructor(alg) => {
+                let alg = ast::convert_algorithm(alg, &name, &context, symbols)
+                    .map_err(|err| compile_error(type_name, &name, err))?;
+                kinds.insert(name, AlgorithmKind::Constructor);
+                algorithms.push(Rc::new(alg));
             },
             Item::Role(role) => {
                 roles.insert(name, role);
             },
+            Item::Data(_) | Item::State { .. } => {
+                // data and state declarations are only needed by the type
+                // checker
+            },
         }
     }
 
-    ObjectType { interfaces, algorithms }
+    Ok(ObjectType {
+        interfaces,
+        algorithms,
+        algorithm_ids,
+        tables,
+        kinds,
+        roles,
+    })
 }
 
-pub fn get_algorithm_name<'a>(
-    types: &'a Dict<ObjectType>,
+// only reachable if the type checker missed something
+fn compile_error(
+    type_name: &str,
+    algorithm_name: &str,
+    err: ast::CompileError,
+) -> typeck::TypeError {
+    typeck::TypeError {
+        type_name: type_name.into(),
+        algorithm_name: algorithm_name.into(),
+        line: err.line,
+        message: err.message,
+    }
+}
 
-    object_type_name: &String,
-    interface_name: &String,
-    method_name: &String,
-) -> &'a String {
-    let object_type = &types[object_type_name];
-    let interface = &object_type.interfaces[interface_name];
-    &interface.implementors[method_name]
+/// Finds the algorithm that implements a method of one of a type's
+/// interfaces.
+pub fn get_implementor(
+    types: &SymbolMap<ObjectType>,
+    symbols: &Interner,
+
+    object_type_name: Symbol,
+    interface_name: Symbol,
+    method_name: Symbol,
+) -> Result<Rc<runtime::Algorithm>, FlopError> {
+    let object_type = get_type(types, symbols, object_type_name)?;
+    let table = object_type.tables.get(&interface_name)
+        .ok_or_else(|| FlopError::UnknownInterface {
+            type_name: symbols.name(object_type_name).into(),
+            interface_name: symbols.name(interface_name).into(),
+        })?;
+    let id = table.get(&method_name)
+        .ok_or_else(|| FlopError::UnknownMethod {
+            type_name: symbols.name(object_type_name).into(),
+            interface_name: symbols.name(interface_name).into(),
+            method_name: symbols.name(method_name).into(),
+        })?;
+    Ok(Rc::clone(&object_type.algorithms[*id]))
 }
 
-pub fn get_algorithm<'a>(
-    types: &Dict<ObjectType>,
+pub fn get_algorithm(
+    types: &SymbolMap<ObjectType>,
+    symbols: &Interner,
 
-    object_type_name: &String,
-    alg_name: &String,
-) -> &'static runtime::Algorithm {
-    let object_type = &types[object_type_name];
-    let result: *const runtime::Algorithm = &object_type.algorithms[alg_name];
+    object_type_name: Symbol,
+    alg_name: Symbol,
+) -> Result<Rc<runtime::Algorithm>, FlopError> {
+    let object_type = get_type(types, symbols, object_type_name)?;
+    let id = object_type.algorithm_ids.get(&alg_name)
+        .ok_or_else(|| FlopError::UnknownAlgorithm {
+            type_name: symbols.name(object_type_name).into(),
+            algorithm_name: symbols.name(alg_name).into(),
+        })?;
+    Ok(Rc::clone(&object_type.algorithms[*id]))
+}
 
-    // safe because we dont edit code at runtime
-    // way better than cloning
-    // if you get a segfault, try cloning code again i guess?
-    // should probably stop FlopInstance from exposing these mutably
-    unsafe { &*result }
+/// Finds an algorithm by the id it had when the caller was compiled, or by
+/// name if the types have been replaced since.
+pub fn get_algorithm_by_id(
+    types: &SymbolMap<ObjectType>,
+    symbols: &Interner,
+
+    object_type_name: Symbol,
+    id: usize,
+    alg_name: Symbol,
+) -> Result<Rc<runtime::Algorithm>, FlopError> {
+    let object_type = get_type(types, symbols, object_type_name)?;
+    match object_type.algorithms.get(id) {
+        Some(alg) if alg.symbol == alg_name => Ok(Rc::clone(alg)),
+        _ => get_algorithm(types, symbols, object_type_name, alg_name),
+    }
 }
 
+fn get_type<'a>(
+    types: &'a SymbolMap<ObjectType>,
+    symbols: &Interner,
+    object_type_name: Symbol,
+) -> Result<&'a ObjectType, FlopError> {
+    types.get(&object_type_name).ok_or_else(|| {
+        FlopError::UnknownType(symbols.name(object_type_name).into())
+    })
+}
@@ -5,12 +5,24 @@ extern crate totem_cell;
 
 pub mod ast;
 pub mod data;
+pub mod debugger;
+pub mod error;
 pub mod event;
+pub mod imports;
 pub mod instance;
 pub mod item;
 pub mod load_types;
 lalrpop_mod!(pub parser);
+pub mod realtime;
+pub mod reload;
+pub mod repl;
+pub mod roles;
 pub mod runtime;
+pub mod save;
+pub mod symbol;
+pub mod trace;
+pub mod trivia;
+pub mod typeck;
 
 pub mod prelude {
     pub use instance::Flop;
@@ -1,37 +1,780 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use lalrpop_util::ParseError;
 
 use prelude::*;
 
+use ast;
+use imports::{ImportError, Scope};
 use item;
 use parser;
+use roles;
+use symbol::{Interner, SymbolMap};
+use trivia;
+use typeck;
+
+// the extension of flop files
+const EXTENSION: &str = "flop";
 
-pub fn get_types(dirpath: &str) -> Dict<item::ObjectType> {
-    let parser = parser::ClassParser::new();
-    let paths = fs::read_dir(dirpath)
-        .expect("Failed to open directory");
+pub struct LoadError {
+    pub path: PathBuf,
+    pub kind: LoadErrorKind,
+}
+
+pub enum LoadErrorKind {
+    Io(io::Error),
+    Parse(SyntaxError),
+    Type(typeck::TypeError),
+    Conformance(roles::ConformanceError),
+    Import {
+        line: usize,
+        error: ImportError,
+    },
+    // a class, or a role outside any class, that was declared before
+    Duplicate {
+        line: usize,
+        // "class" or "role"
+        item: &'static str,
+        name: String,
+        first: PathBuf,
+    },
+    // only reported by `check_file_names`
+    NameMismatch {
+        item_name: String,
+        file_name: String,
+    },
+}
+
+pub struct SyntaxError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    // `None` means the file ended early
+    pub token: Option<String>,
+    pub expected: Vec<String>,
+    pub snippet: String,
+}
+
+impl LoadError {
+    fn io(path: &Path, err: io::Error) -> Self {
+        let path = path.to_path_buf();
+        let kind = LoadErrorKind::Io(err);
+        LoadError { path, kind }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path.display();
+        match self.kind {
+            LoadErrorKind::Io(ref err) => {
+                write!(f, "{}: {}", path, err)
+            },
+            LoadErrorKind::Parse(ref err) => {
+                writeln!(
+                    f,
+                    "{}:{}:{}: {}",
+                    path,
+                    err.line,
+                    err.column,
+                    err.message,
+                )?;
+                if err.expected.len() > 0 {
+                    writeln!(f, "expected one of: {}", err.expected.join(", "))?;
+                }
+                write!(f, "{}", err.snippet)
+            },
+            LoadErrorKind::Type(ref err) => {
+                write!(f, "{}:{}", path, err)
+            },
+            LoadErrorKind::Conformance(ref err) => {
+                write!(f, "{}: {}", path, err)
+            },
+            LoadErrorKind::Import { line, ref error } => {
+                write!(f, "{}:{}: {}", path, line, error)
+            },
+            LoadErrorKind::Duplicate { line, item, ref name, ref first } => {
+                write!(
+                    f,
+                    "{}:{}: {} '{}' is already declared in {}",
+                    path,
+                    line,
+                    item,
+                    name,
+                    first.display(),
+                )
+            },
+            LoadErrorKind::NameMismatch { ref item_name, ref file_name } => {
+                write!(
+                    f,
+                    "{}: item name did not match file name: '{}' in '{}'",
+                    path,
+                    item_name,
+                    file_name,
+                )
+            },
+        }
+    }
+}
+
+impl fmt::Debug for LoadError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// a file that has been parsed but not yet checked or collected
+struct ParsedFile {
+    path: PathBuf,
+    source_name: String,
+    // the module its classes belong to, empty for none
+    module: String,
+    imports: Vec<item::Import>,
+    classes: Vec<ParsedClass>,
+    // roles outside any class, which are global
+    roles: Vec<(usize, String, item::Role)>,
+    // data types outside any class, as `item::Item::Data`
+    data: Vec<(String, item::Item)>,
+    lines: ast::LineIndex,
+}
+
+struct ParsedClass {
+    // the full name, with the module in front
+    name: String,
+    offset: usize,
+    items: Vec<(String, item::Item)>,
+}
+
+/// Everything loaded from a content directory, or other sources.
+pub struct Content {
+    pub types: SymbolMap<item::ObjectType>,
+    // roles that belong to no class, kept in their own namespace
+    pub roles: Dict<item::Role>,
+    pub symbols: Interner,
+}
+
+/// One file's worth of flop code.
+pub struct Source {
+    // the path of the file relative to the content directory, like
+    // `units/Soldier.flop`, which says the module of its classes
+    pub name: String,
+    // where the text came from, for error messages
+    pub path: PathBuf,
+    pub text: String,
+}
+
+/// Somewhere that sources can be loaded from.
+pub trait SourceProvider {
+    /// Every source there is, adding an error for each one that couldn't be
+    /// read.
+    fn sources(self: &mut Self, errors: &mut Vec<LoadError>) -> Vec<Source>;
+}
+
+/// A content directory, whose files each hold any number of classes, roles
+/// and data types. Classes in subdirectories belong to modules, so a class
+/// `Soldier` in `units/army.flop` is `units.Soldier`.
+///
+/// Only files with the flop extension are loaded, and hidden files and
+/// symlinked directories are skipped, so editor swap files and the like are
+/// left alone, and a link back up the tree can't loop forever.
+pub struct Directory {
+    path: PathBuf,
+    extension: String,
+}
+
+impl Directory {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let extension = EXTENSION.into();
+        Directory { path, extension }
+    }
+
+    /// Loads files with the given extension instead of the usual one.
+    pub fn with_extension(self: Self, extension: &str) -> Self {
+        let extension = extension.into();
+        Directory { extension, ..self }
+    }
+
+    // reads every wanted file under `dirpath`, whose path relative to the
+    // content directory is `prefix`
+    fn visit(
+        self: &Self,
+        dirpath: &Path,
+        prefix: &str,
+        sources: &mut Vec<Source>,
+        errors: &mut Vec<LoadError>,
+    ) {
+        for path in read_dir(dirpath, errors) {
+            let file_name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(file_name) if !file_name.starts_with(".") => file_name,
+                _ => continue,
+            };
+            let name = format!("{}{}", prefix, file_name);
+            if path.is_dir() {
+                let linked = fs::symlink_metadata(&path)
+                    .map_or(true, |meta| meta.file_type().is_symlink());
+                if !linked {
+                    self.visit(&path, &format!("{}/", name), sources, errors);
+                }
+                continue;
+            }
+            if path.extension() == Some(self.extension.as_ref()) {
+                read_source(name, path.clone(), sources, errors);
+            }
+        }
+    }
+}
+
+impl SourceProvider for Directory {
+    fn sources(self: &mut Self, errors: &mut Vec<LoadError>) -> Vec<Source> {
+        let mut sources = Vec::new();
+        self.visit(&self.path, "", &mut sources, errors);
+        sources
+    }
+}
+
+/// Sources that are already in memory, as `(name, text)` pairs named like
+/// `Source::name`.
+impl<N: AsRef<str>, T: AsRef<str>> SourceProvider for Vec<(N, T)> {
+    fn sources(self: &mut Self, _: &mut Vec<LoadError>) -> Vec<Source> {
+        self.iter()
+            .map(|&(ref name, ref text)| Source {
+                name: name.as_ref().into(),
+                path: PathBuf::from(name.as_ref()),
+                text: text.as_ref().into(),
+            })
+            .collect()
+    }
+}
+
+/// Parses every file under a directory, reporting every file that failed
+/// rather than just the first.
+pub fn get_types(dirpath: &str) -> Result<Content, Vec<LoadError>> {
+    get_types_with(dirpath, Interner::new())
+}
+
+/// Like `get_types`, but adding names to an existing interner, so that the
+/// symbols already held by a running simulation stay valid.
+pub fn get_types_with(
+    dirpath: &str,
+    symbols: Interner,
+) -> Result<Content, Vec<LoadError>> {
+    load(&mut Directory::new(dirpath), symbols)
+}
+
+/// Parses and checks everything a provider has, adding names to `symbols`.
+pub fn load<P: SourceProvider + ?Sized>(
+    provider: &mut P,
+    mut symbols: Interner,
+) -> Result<Content, Vec<LoadError>> {
+    let parser = parser::FileParser::new();
+
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    for source in provider.sources(&mut errors) {
+        match load_file(&parser, source) {
+            Ok(file) => files.push(file),
+            Err(err) => errors.push(err),
+        }
+    }
+    find_duplicates(&files, &mut errors);
+
+    // class and role names can only be made full once every file has parsed
+    if errors.len() == 0 {
+        let names: HashSet<String> = files
+            .iter()
+            .flat_map(|file| &file.classes)
+            .map(|class| class.name.clone())
+            .collect();
+        let role_names: HashSet<String> = files
+            .iter()
+            .flat_map(|file| &file.roles)
+            .map(|&(_, ref name, _)| name.clone())
+            .collect();
+        for file in &mut files {
+            let ParsedFile {
+                ref path,
+                ref module,
+                ref imports,
+                ref mut classes,
+                ref mut roles,
+                ref mut data,
+                ref lines,
+                ..
+            } = *file;
+            let mut scope = Scope::new(module, &names, &role_names);
+            for import in imports {
+                if let Err(error) = scope.import(import) {
+                    let line = lines.line(import.offset);
+                    let kind = LoadErrorKind::Import { line, error };
+                    errors.push(LoadError { path: path.clone(), kind });
+                }
+            }
+            for class in classes {
+                scope.qualify_items(&mut class.items);
+            }
+            for &mut (_, _, ref mut role) in roles {
+                scope.qualify_role(role);
+            }
+            scope.qualify_items(data);
+        }
+    }
+
+    // calls into other classes can only be checked once every file has
+    // parsed
+    if errors.len() == 0 {
+        let signatures: Dict<typeck::Class> = files
+            .iter()
+            .flat_map(|file| &file.classes)
+            .map(|class| (class.name.clone(), typeck::Class::new(&class.items)))
+            .collect();
+        let mut data_types = typeck::DataTypes::new();
+        for file in &files {
+            let mut type_errors = data_types.declare(
+                &String::new(),
+                &file.data,
+                &file.lines,
+            );
+            for class in &file.classes {
+                type_errors.extend(data_types.declare(
+                    &class.name,
+                    &class.items,
+                    &file.lines,
+                ));
+            }
+            for err in type_errors {
+                let path = file.path.clone();
+                let kind = LoadErrorKind::Type(err);
+                errors.push(LoadError { path, kind });
+            }
+        }
+        for file in &files {
+            for class in &file.classes {
+                let type_errors = typeck::check_class(
+                    &signatures,
+                    &data_types,
+                    &class.name,
+                    &class.items,
+                    &file.lines,
+                );
+                for err in type_errors {
+                    let path = file.path.clone();
+                    let kind = LoadErrorKind::Type(err);
+                    errors.push(LoadError { path, kind });
+                }
+            }
+        }
+    }
+    if errors.len() > 0 {
+        return Err(errors);
+    }
 
     let mut types = Dict::new();
-    for path in paths {
-        use std::io::Read;
-
-        let path = path.expect("IO error getting paths").path();
-        let path_str = path.to_str().expect("invalid path name");
-        let file_name = path.file_stem().unwrap().to_str().unwrap().into();
-        let mut file = fs::File::open(path_str).expect("Failed to open file");
-        let mut content = String::new();
-        file.read_to_string(&mut content).expect("Failed to read file");
-
-        let (iname, parsed) = parser.parse(&content).unwrap();
-        if iname != file_name {
-            panic!(
-                "Class name did not match file name: '{}' in '{}'",
-                iname,
-                file_name,
+    let mut type_paths = Dict::new();
+    let mut roles = Dict::new();
+    for file in files {
+        let ParsedFile { path, source_name, classes, lines, .. } = file;
+        let source_name: Rc<str> = Rc::from(source_name);
+        for class in classes {
+            let type_name: Rc<str> = Rc::from(class.name.as_str());
+            let object_type = item::collect(
+                class.items,
+                &type_name,
+                &source_name,
+                &lines,
+                &mut symbols,
             );
+            let object_type = match object_type {
+                Ok(object_type) => object_type,
+                Err(err) => {
+                    let kind = LoadErrorKind::Type(err);
+                    errors.push(LoadError { path: path.clone(), kind });
+                    continue;
+                },
+            };
+            type_paths.insert(class.name.clone(), path.clone());
+            types.insert(class.name, object_type);
+        }
+        for (_, name, role) in file.roles {
+            roles.insert(name, role);
         }
-        types.insert(file_name, parsed);
     }
 
-    types
+    // roles can only be resolved once every file has loaded
+    {
+        for err in roles::check_roles(&types, &roles, &symbols) {
+            let path = type_paths[&err.type_name].clone();
+            let kind = LoadErrorKind::Conformance(err);
+            errors.push(LoadError { path, kind });
+        }
+    }
+
+    if errors.len() > 0 {
+        return Err(errors);
+    }
+    let types = types
+        .into_iter()
+        .map(|(name, object_type)| (symbols.intern(&name), object_type))
+        .collect();
+    Ok(Content { types, roles, symbols })
 }
 
+// the entries of a directory, or none if it can't be read
+fn read_dir(dirpath: &Path, errors: &mut Vec<LoadError>) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dirpath) {
+        Ok(entries) => entries,
+        Err(err) => {
+            errors.push(LoadError::io(dirpath, err));
+            return Vec::new();
+        },
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => paths.push(entry.path()),
+            Err(err) => errors.push(LoadError::io(dirpath, err)),
+        }
+    }
+    // so that the first of two duplicates is always the same one
+    paths.sort();
+    paths
+}
+
+fn read_source(
+    name: String,
+    path: PathBuf,
+    sources: &mut Vec<Source>,
+    errors: &mut Vec<LoadError>,
+) {
+    match fs::read_to_string(&path) {
+        Ok(text) => sources.push(Source { name, path, text }),
+        Err(err) => errors.push(LoadError::io(&path, err)),
+    }
+}
+
+// the file name of a source without its extension, and the modules before
+// it
+fn split_name(source_name: &str) -> (Vec<&str>, String) {
+    let mut modules: Vec<&str> = source_name.split('/').collect();
+    let file_name = modules.pop().unwrap_or("");
+    let stem = Path::new(file_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("")
+        .to_string();
+    (modules, stem)
+}
+
+fn load_file(
+    parser: &parser::FileParser,
+    source: Source,
+) -> Result<ParsedFile, LoadError> {
+    let Source { name: source_name, path, text } = source;
+    let (modules, _) = split_name(&source_name);
+    let module = modules.join(".");
+
+    let file = parse_file(parser, &text)
+        .map_err(|err| {
+            let kind = LoadErrorKind::Parse(err);
+            LoadError { path: path.clone(), kind }
+        })?;
+    let mut classes = Vec::new();
+    let mut roles = Vec::new();
+    let mut data = Vec::new();
+    for (offset, name, file_item) in file.items {
+        match file_item {
+            item::FileItem::Class(items) => {
+                let name = qualified(&module, name);
+                classes.push(ParsedClass { name, offset, items });
+            },
+            item::FileItem::Role(role) => {
+                roles.push((offset, qualified(&module, name), role));
+            },
+            item::FileItem::Data(decl) => {
+                data.push((name, item::Item::Data(decl)));
+            },
+        }
+    }
+
+    let imports = file.imports;
+    let lines = ast::LineIndex::new(&text);
+    Ok(ParsedFile {
+        path,
+        source_name,
+        module,
+        imports,
+        classes,
+        roles,
+        data,
+        lines,
+    })
+}
+
+// the full name of something declared in `module`
+fn qualified(module: &str, name: String) -> String {
+    if module.len() > 0 {
+        format!("{}.{}", module, name)
+    } else {
+        name
+    }
+}
+
+// reports each class, and each role outside any class, where it is declared
+// again; classes and roles have separate namespaces
+fn find_duplicates(files: &[ParsedFile], errors: &mut Vec<LoadError>) {
+    let mut declared: HashMap<(&str, &str), &Path> = HashMap::new();
+    for file in files {
+        let classes = file.classes
+            .iter()
+            .map(|class| (class.offset, "class", &class.name));
+        let roles = file.roles
+            .iter()
+            .map(|&(offset, ref name, _)| (offset, "role", name));
+        for (offset, item, name) in classes.chain(roles) {
+            if let Some(first) = declared.get(&(item, name.as_str())) {
+                let kind = LoadErrorKind::Duplicate {
+                    line: file.lines.line(offset),
+                    item,
+                    name: name.clone(),
+                    first: first.to_path_buf(),
+                };
+                errors.push(LoadError { path: file.path.clone(), kind });
+                continue;
+            }
+            declared.insert((item, name), &file.path);
+        }
+    }
+}
+
+/// Reports every source that declares nothing named after its file, for
+/// content that keeps to one class or role per file. Sources that can't be
+/// read or parsed are left for `load` to report.
+pub fn check_file_names<P: SourceProvider + ?Sized>(
+    provider: &mut P,
+) -> Vec<LoadError> {
+    let parser = parser::FileParser::new();
+    let mut errors = Vec::new();
+    for source in provider.sources(&mut Vec::new()) {
+        let file = match parse_file(&parser, &source.text) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let (_, file_name) = split_name(&source.name);
+        let names: Vec<String> = file.items
+            .into_iter()
+            .map(|(_, name, _)| name)
+            .collect();
+        if !names.contains(&file_name) {
+            let item_name = names.join(", ");
+            let kind = LoadErrorKind::NameMismatch { item_name, file_name };
+            errors.push(LoadError { path: source.path, kind });
+        }
+    }
+    errors
+}
+
+/// Parses a whole file, keeping its comments.
+pub fn parse_file(
+    parser: &parser::FileParser,
+    text: &str,
+) -> Result<item::File, SyntaxError> {
+    let (code, comments) = trivia::strip(text)
+        .map_err(|offset| unterminated_comment(text, offset))?;
+    // the code has the same offsets as the text, which errors should show
+    let mut file = parser.parse(&code)
+        .map_err(|err| syntax_error(text, err))?;
+    file.comments = comments;
+    Ok(file)
+}
+
+pub(crate) fn unterminated_comment(content: &str, start: usize) -> SyntaxError {
+    let message = "unterminated block comment".into();
+    let token = Some("/*".into());
+    let expected = vec!["\"*/\"".into()];
+    let (line, column, snippet) = locate(content, start, start + 2);
+    SyntaxError { message, line, column, token, expected, snippet }
+}
+
+pub(crate) fn syntax_error<T, E: fmt::Display>(
+    content: &str,
+    err: ParseError<usize, T, E>,
+) -> SyntaxError {
+    let (message, start, end, expected) = match err {
+        ParseError::InvalidToken { location } => {
+            let message = "invalid token".into();
+            let location = boundary(content, location);
+            let width = content[location..]
+                .chars()
+                .next()
+                .map_or(1, char::len_utf8);
+            (message, location, location + width, Vec::new())
+        },
+        ParseError::UnrecognizedToken { token: Some((l, _, r)), expected } => {
+            let message = "unexpected token".into();
+            (message, l, r, expected)
+        },
+        ParseError::UnrecognizedToken { token: None, expected } => {
+            let message = "unexpected end of file".into();
+            (message, content.len(), content.len(), expected)
+        },
+        ParseError::ExtraToken { token: (l, _, r) } => {
+            let message = "extra token".into();
+            (message, l, r, Vec::new())
+        },
+        ParseError::User { error } => {
+            (error.to_string(), 0, 0, Vec::new())
+        },
+    };
+
+    let start = boundary(content, start);
+    let end = boundary(content, end);
+    let token = if start < end {
+        Some(content[start..end].to_string())
+    } else {
+        None
+    };
+    let message = match token {
+        Some(ref token) => format!("{} '{}'", message, token),
+        None => message,
+    };
+    let (line, column, snippet) = locate(content, start, end);
+
+    SyntaxError { message, line, column, token, expected, snippet }
+}
+
+// returns 1-based line and column, and the source line with a caret under
+// the offending span
+pub(crate) fn locate(
+    content: &str,
+    start: usize,
+    end: usize,
+) -> (usize, usize, String) {
+    let start = boundary(content, start);
+    let end = boundary(content, end);
+    let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[start..].find('\n').map_or(content.len(), |i| start + i);
+    let line = content[..start].matches('\n').count() + 1;
+    let column = content[line_start..start].chars().count() + 1;
+
+    let text = &content[line_start..line_end];
+    let padding: String = content[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = content[start..end.max(start).min(line_end)].chars().count();
+    let carets = "^".repeat(width.max(1));
+    let snippet = format!("{:>4} | {}\n     | {}{}", line, text, padding, carets);
+
+    (line, column, snippet)
+}
+
+// the nearest offset at or before `offset` that starts a character, so that
+// slicing there can't panic
+fn boundary(content: &str, offset: usize) -> usize {
+    let mut offset = offset.min(content.len());
+    while !content.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a soldier whose `go` takes `params`, walking by the role in its module
+    fn soldier(params: &str) -> String {
+        format!("
+            class Soldier {{
+                state Ready {{}}
+                Move Walk {{ go }}
+
+                ctor make() {{
+                    self.data = Ready {{}};
+                }}
+
+                fun go({}) {{
+                    print(1);
+                }}
+            }}
+        ", params)
+    }
+
+    fn load_units(soldier: &str) -> Result<Content, Vec<LoadError>> {
+        let mut sources = vec![
+            ("units/Move.flop", "role Move { go: fun() }"),
+            ("other/Move.flop", "role Move { stop: fun() }"),
+            ("units/Soldier.flop", soldier),
+        ];
+        load(&mut sources, Interner::new())
+    }
+
+    #[test]
+    fn roles_belong_to_modules() {
+        let content = match load_units(&soldier("")) {
+            Ok(content) => content,
+            Err(errors) => panic!("{}", errors[0]),
+        };
+        let mut roles: Vec<&String> = content.roles.keys().collect();
+        roles.sort();
+        assert_eq!(roles, vec!["other.Move", "units.Move"]);
+
+        // only checked against the role if its short name was found
+        assert!(load_units(&soldier("x")).is_err());
+    }
+
+    // a root leading a soldier two modules down, named by its full path
+    fn load_army(root: &str) -> Result<Content, Vec<LoadError>> {
+        let mut sources = vec![
+            ("units/army/Soldier.flop", "
+                class Soldier {
+                    state Ready {}
+
+                    ctor make() {
+                        self.data = Ready {};
+                    }
+                }
+            "),
+            ("Root.flop", root),
+        ];
+        load(&mut sources, Interner::new())
+    }
+
+    const ARMY_ROOT: &str = "
+        data Squad { Squad { soldier: units.army.Soldier } }
+
+        class Root {
+            state Leading { squad: Squad }
+
+            ctor init() {
+                soldier = units.army.Soldier.make();
+                self.data = Leading { squad = self.squad(soldier) };
+            }
+
+            fun squad(soldier: units.army.Soldier) -> Squad {
+                return(Squad { soldier = soldier });
+            }
+        }
+    ";
+
+    #[test]
+    fn types_named_with_their_module() {
+        if let Err(errors) = load_army(ARMY_ROOT) {
+            panic!("{}", errors[0]);
+        }
+
+        // modules are never relative to anything
+        let wrong = ARMY_ROOT.replace("= units.army", "= army");
+        let errors = load_army(&wrong).err().unwrap();
+        assert!(errors[0].to_string().contains("type 'army.Soldier'"));
+        let wrong = ARMY_ROOT.replace(": units.army", ": units");
+        let errors = load_army(&wrong).err().unwrap();
+        assert!(errors[0].to_string().contains("expects units.Soldier"));
+    }
+}
@@ -1,24 +1,392 @@
 extern crate flop;
 
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::process;
+use std::time::Duration;
+
 use flop::prelude::*;
 
-use flop::event::EventQueue;
+use flop::data;
+use flop::debugger::{Breakpoint, Debugger};
+use flop::error::FlopError;
+use flop::event::{self, EventQueue};
 use flop::instance::FlopInstance;
-use flop::load_types::get_types;
+use flop::load_types::{check_file_names, get_types, Directory};
+use flop::realtime::{Driver, Speed};
+use flop::reload::Watcher;
+use flop::repl::Repl;
+use flop::runtime;
+use flop::trace::Tracer;
+
+const USAGE: &str = "\
+usage: flop [options] [DATA_DIR [ROOT [CTOR [ARGS...]]]]
+       flop repl [DATA_DIR]
+
+Loads every class under DATA_DIR (default ./data), constructs ROOT (default
+Root) with its constructor CTOR (default init) and the given numeric ARGS, then
+runs events until the queue is empty. Classes in subdirectories are named after
+them, like units.Soldier. The repl loads DATA_DIR and then reads flop code and
+commands from the terminal.
+
+options:
+    --until TIME    stop before the first event after simulated time TIME
+    --events N      stop after running N events
+    --speed X       run X simulated seconds per real second, or 'max'
+    --debug         step through the code from the first statement
+    --break SPEC    stop at Type, Type.alg, Type:line or Type.alg:line
+    --trace FILE    log what happens as JSON lines to FILE, or - for stdout
+    --watch         reload DATA_DIR between events whenever its files change
+    --check-names   refuse files that declare nothing named after the file
+    -h, --help      print this message";
+
+// how often --watch looks at the content directory
+const WATCH_INTERVAL_MS: u64 = 500;
+
+struct Options {
+    data_dir: String,
+    root_type: String,
+    ctor_name: String,
+    args: Vec<data::Field>,
+    until: Option<f64>,
+    max_events: Option<u64>,
+    speed: Option<Speed>,
+    debug: bool,
+    breakpoints: Vec<Breakpoint>,
+    trace: Option<String>,
+    watch: bool,
+    check_names: bool,
+    repl: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut positional = Vec::new();
+    let mut until = None;
+    let mut max_events = None;
+    let mut speed = None;
+    let mut debug = false;
+    let mut breakpoints = Vec::new();
+    let mut trace = None;
+    let mut watch = false;
+    let mut check_names = false;
+
+    let mut args = env::args().skip(1).peekable();
+    let repl = args.peek().map_or(false, |arg| arg == "repl");
+    if repl {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            "--until" => {
+                let value = args.next()
+                    .ok_or("--until needs a time")?;
+                let time: f64 = value.parse()
+                    .map_err(|_| format!("invalid time '{}'", value))?;
+                until = Some(time);
+            },
+            "--events" => {
+                let value = args.next()
+                    .ok_or("--events needs a count")?;
+                let count: u64 = value.parse()
+                    .map_err(|_| format!("invalid event count '{}'", value))?;
+                max_events = Some(count);
+            },
+            "--speed" => {
+                let value = args.next()
+                    .ok_or("--speed needs a factor")?;
+                speed = match value.parse::<f64>() {
+                    _ if value == "max" => Some(Speed::Max),
+                    Ok(scale) if scale > 0.0 => Some(Speed::Scale(scale)),
+                    _ => return Err(format!("invalid speed '{}'", value)),
+                };
+            },
+            "--debug" => debug = true,
+            "--watch" => watch = true,
+            "--check-names" => check_names = true,
+            "--break" => {
+                let value = args.next()
+                    .ok_or("--break needs a breakpoint")?;
+                let breakpoint = Breakpoint::parse(&value)
+                    .ok_or_else(|| format!("invalid breakpoint '{}'", value))?;
+                breakpoints.push(breakpoint);
+            },
+            "--trace" => {
+                let value = args.next()
+                    .ok_or("--trace needs a file")?;
+                trace = Some(value);
+            },
+            // anything else starting with a dash is a typo, unless it is a
+            // negative number for the constructor
+            _ if arg.starts_with("-") && arg.parse::<f64>().is_err() => {
+                return Err(format!("unknown option '{}'", arg));
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    if repl && positional.len() > 1 {
+        return Err("the repl only takes a content directory".into());
+    }
+    let mut positional = positional.into_iter();
+    let data_dir = positional.next().unwrap_or("./data".into());
+    let root_type = positional.next().unwrap_or("Root".into());
+    let ctor_name = positional.next().unwrap_or("init".into());
+    let mut ctor_args = Vec::new();
+    for arg in positional {
+        let num: f64 = arg.parse()
+            .map_err(|_| format!("invalid numeric argument '{}'", arg))?;
+        ctor_args.push(data::Field::Num(num));
+    }
+
+    Ok(Options {
+        data_dir,
+        root_type,
+        ctor_name,
+        args: ctor_args,
+        until,
+        max_events,
+        speed,
+        debug,
+        breakpoints,
+        trace,
+        watch,
+        check_names,
+        repl,
+    })
+}
+
+// the host, which counts failed events so that they affect the exit code
+struct Runner {
+    instance: FlopInstance,
+    errors: usize,
+    debugger: Option<Debugger>,
+}
+
+impl AsMut<FlopInstance> for Runner {
+    fn as_mut(self: &mut Self) -> &mut FlopInstance {
+        &mut self.instance
+    }
+}
+
+impl AsMut<event::EventQueue> for Runner {
+    fn as_mut(self: &mut Self) -> &mut event::EventQueue {
+        &mut self.instance.event_queue
+    }
+}
+
+impl Flop for Runner {
+    fn extern_call(
+        self: &mut Self,
+        func_name: &String,
+        args: Vec<data::Field>,
+    ) -> Result<Vec<data::Field>, FlopError> {
+        self.instance.extern_call(func_name, args)
+    }
+
+    fn event_error(self: &mut Self, object: data::Object, error: FlopError) {
+        drop(object);
+        eprintln!("Error: {}", error);
+        self.errors += 1;
+    }
+
+    fn before_statement(
+        self: &mut Self,
+        object: &data::Object,
+        locals: runtime::Locals,
+    ) {
+        let quit = match self.debugger {
+            Some(ref mut debugger) => {
+                !debugger.before_statement(&self.instance, object, locals)
+            },
+            None => false,
+        };
+        if quit {
+            finish_trace(&mut self.instance);
+            process::exit(0);
+        }
+    }
+}
+
+// process::exit skips destructors, so buffered records need flushing by hand
+fn finish_trace(instance: &mut FlopInstance) {
+    if let Some(ref mut tracer) = instance.tracer {
+        if let Some(err) = tracer.error() {
+            eprintln!("flop: could not write trace: {}", err);
+        } else if let Err(err) = tracer.flush() {
+            eprintln!("flop: could not write trace: {}", err);
+        }
+    }
+}
+
+// edits that don't load, or that would strand a waiting object, are
+// reported and otherwise ignored
+fn reload(game: &mut Runner, data_dir: &str) {
+    match game.instance.reload(data_dir) {
+        Ok(reload) => eprintln!("flop: reloaded {}: {}", data_dir, reload),
+        Err(err) => eprintln!("flop: not reloaded: {}", err),
+    }
+    if let Some(ref mut debugger) = game.debugger {
+        debugger.forget_sources();
+    }
+}
 
 fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("flop: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        },
+    };
+
     let now = Time::try_from(0.0).unwrap();
     // only do this once ok?
     let totem = unsafe { Totem::new() };
     let event_queue = EventQueue::new(now);
-    let types = get_types("./data");
+    if options.check_names {
+        let errors = check_file_names(&mut Directory::new(&options.data_dir));
+        for error in &errors {
+            eprintln!("{}\n", error);
+        }
+        if errors.len() > 0 {
+            process::exit(1);
+        }
+    }
+    let content = match get_types(&options.data_dir) {
+        Ok(content) => content,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}\n", error);
+            }
+            process::exit(1);
+        },
+    };
+    if options.repl {
+        let instance = FlopInstance::new(
+            totem,
+            event_queue,
+            content,
+        );
+        let mut repl = Repl::new(options.data_dir, instance);
+        let stdin = io::stdin();
+        repl.run(stdin.lock());
+        return;
+    }
+    let known = content.symbols
+        .get(&options.root_type)
+        .map_or(false, |name| content.types.contains_key(&name));
+    if !known {
+        eprintln!("Error: unknown type '{}'", options.root_type);
+        process::exit(1);
+    }
 
-    let mut game = FlopInstance { totem, event_queue, types };
+    let mut instance = FlopInstance::new(
+        totem,
+        event_queue,
+        content,
+    );
+    instance.tracer = match options.trace.as_ref().map(String::as_str) {
+        Some("-") => Some(Tracer::new(io::stdout())),
+        Some(path) => match File::create(path) {
+            Ok(file) => Some(Tracer::new(BufWriter::new(file))),
+            Err(err) => {
+                eprintln!("flop: {}: {}", path, err);
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+    let debugger = if options.debug || options.breakpoints.len() > 0 {
+        let mut debugger = Debugger::new(options.data_dir.clone().into());
+        if !options.debug {
+            // only stop at breakpoints
+            debugger.continue_to_breakpoint();
+        }
+        for breakpoint in options.breakpoints {
+            debugger.add_breakpoint(breakpoint);
+        }
+        Some(debugger)
+    } else {
+        None
+    };
+    let mut game = Runner { instance, errors: 0, debugger };
 
-    FlopInstance::run(
+    let until = match options.until.map(Time::try_from) {
+        Some(Ok(time)) => Some(time),
+        Some(Err(_)) => {
+            eprintln!("flop: invalid time '{}'", options.until.unwrap());
+            process::exit(2);
+        },
+        None => None,
+    };
+
+    let root = FlopInstance::start(
         &mut game,
 
-        "Root".into(),
-        "init".into(),
+        options.root_type,
+        options.ctor_name,
+        options.args,
     );
+    let _root = match root {
+        Ok(root) => root,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            finish_trace(&mut game.instance);
+            process::exit(1);
+        },
+    };
+
+    // the cli has no way to change the speed once running, so the control
+    // sender is dropped; the driver then never pauses
+    let mut driver = options.speed.map(|speed| {
+        let (driver, _) = Driver::new(now, speed);
+        driver
+    });
+    let mut watcher = if options.watch {
+        let interval = Duration::from_millis(WATCH_INTERVAL_MS);
+        Some(Watcher::new(&options.data_dir, interval))
+    } else {
+        None
+    };
+    let mut count = 0;
+    loop {
+        if options.max_events.map_or(false, |max| count >= max) {
+            break;
+        }
+        match (game.instance.next_event_time(), until) {
+            (None, _) => break,
+            (Some(next), Some(until)) if next > until => break,
+            _ => (),
+        }
+        if let Some(ref mut watcher) = watcher {
+            if watcher.changed() {
+                reload(&mut game, &options.data_dir);
+            }
+        }
+        if let Some(ref mut debugger) = game.debugger {
+            debugger.begin_event();
+        }
+        match driver {
+            // nothing left that the driver could ever run
+            Some(ref mut driver) => if !driver.step(&mut game) {
+                break;
+            },
+            None => {
+                FlopInstance::step(&mut game, 1);
+            },
+        }
+        count += 1;
+    }
+
+    finish_trace(&mut game.instance);
+    if game.errors > 0 {
+        eprintln!("{} event(s) failed", game.errors);
+        process::exit(1);
+    }
 }
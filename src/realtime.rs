@@ -0,0 +1,197 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prelude::*;
+
+use instance::FlopInstance;
+
+// how many events `tick` runs at most when going as fast as possible, so that
+// looping scripts don't hang the host
+const MAX_BATCH: usize = 1000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speed {
+    Paused,
+    // simulated seconds per wall-clock second
+    Scale(f64),
+    // no sleeping at all
+    Max,
+}
+
+/// Runs events as their simulated times come due on the wall clock. Other
+/// threads change its speed through the sender `new` returns.
+pub struct Driver {
+    speed: Speed,
+    // the simulated time that was reached at `wall_anchor`
+    sim_anchor: f64,
+    wall_anchor: Instant,
+    control: mpsc::Receiver<Speed>,
+}
+
+impl Driver {
+    pub fn new(now: Time, speed: Speed) -> (Self, mpsc::Sender<Speed>) {
+        let (sender, control) = mpsc::channel();
+        let driver = Driver {
+            speed: normalize(speed),
+            sim_anchor: f64::from(now),
+            wall_anchor: Instant::now(),
+            control,
+        };
+        (driver, sender)
+    }
+
+    pub fn speed(self: &Self) -> Speed {
+        self.speed
+    }
+
+    /// Runs every event that is due by now, without blocking, and returns how
+    /// many ran.
+    ///
+    /// Meant to be called once per frame by a host with its own loop.
+    pub fn tick<G: Flop>(self: &mut Self, game: &mut G) -> usize {
+        self.poll(game);
+        match self.speed {
+            Speed::Paused => 0,
+            Speed::Max => FlopInstance::step(game, MAX_BATCH),
+            Speed::Scale(_) => {
+                let target = self.target();
+                match Time::try_from(target) {
+                    Ok(target) => FlopInstance::run_until(game, target),
+                    Err(_) => 0,
+                }
+            },
+        }
+    }
+
+    /// Sleeps until the next event is due, then runs it.
+    ///
+    /// Returns false if there was nothing to run, either because the queue
+    /// is empty or because the driver is paused, or too slow for the next
+    /// event to ever come, and nothing can change its speed.
+    pub fn step<G: Flop>(self: &mut Self, game: &mut G) -> bool {
+        loop {
+            self.poll(game);
+            let next = match instance(game).next_event_time() {
+                Some(next) => f64::from(next),
+                None => return false,
+            };
+
+            let scale = match self.speed {
+                Speed::Max => break,
+                Speed::Paused => {
+                    match self.control.recv() {
+                        Ok(speed) => self.set_speed(game, speed),
+                        Err(_) => return false,
+                    }
+                    continue;
+                },
+                Speed::Scale(scale) => scale,
+            };
+
+            let delay = (next - self.sim_anchor) / scale;
+            let due = match self.wall_anchor.checked_add(duration(delay)) {
+                Some(due) => due,
+                // too far off to ever come, so only a speed change helps
+                None => {
+                    match self.control.recv() {
+                        Ok(speed) => self.set_speed(game, speed),
+                        Err(_) => return false,
+                    }
+                    continue;
+                },
+            };
+            let now = Instant::now();
+            if due <= now {
+                break;
+            }
+            // a speed change wakes us early, and is then handled by `poll`
+            match self.control.recv_timeout(due - now) {
+                Ok(speed) => self.set_speed(game, speed),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    thread::sleep(due - now);
+                    break;
+                },
+            }
+        }
+        FlopInstance::step(game, 1) > 0
+    }
+
+    /// Keeps stepping until the queue is empty or the next event is after
+    /// `until`, returning how many events ran.
+    pub fn run<G: Flop>(
+        self: &mut Self,
+        game: &mut G,
+        until: Option<Time>,
+    ) -> usize {
+        let mut count = 0;
+        loop {
+            if let (Some(until), Some(next)) =
+                (until, instance(game).next_event_time())
+            {
+                if next > until {
+                    break;
+                }
+            }
+            if !self.step(game) {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    // the simulated time that the wall clock has reached
+    fn target(self: &Self) -> f64 {
+        match self.speed {
+            Speed::Paused => self.sim_anchor,
+            Speed::Scale(scale) => {
+                let elapsed = self.wall_anchor.elapsed();
+                let secs = elapsed.as_secs() as f64
+                    + elapsed.subsec_nanos() as f64 * 1e-9;
+                self.sim_anchor + secs * scale
+            },
+            Speed::Max => ::std::f64::INFINITY,
+        }
+    }
+
+    fn poll<G: Flop>(self: &mut Self, game: &mut G) {
+        while let Ok(speed) = self.control.try_recv() {
+            self.set_speed(game, speed);
+        }
+    }
+
+    fn set_speed<G: Flop>(self: &mut Self, game: &mut G, speed: Speed) {
+        let now = f64::from(instance(game).now());
+        // never jump backwards, and don't let time run ahead of the events
+        // that went by at max speed
+        self.sim_anchor = match self.speed {
+            Speed::Max => now,
+            _ => self.target().max(now),
+        };
+        self.wall_anchor = Instant::now();
+        self.speed = normalize(speed);
+    }
+}
+
+// a scale that can't move time forwards is a pause
+fn normalize(speed: Speed) -> Speed {
+    match speed {
+        Speed::Scale(scale) if !(scale > 0.0) => Speed::Paused,
+        speed => speed,
+    }
+}
+
+fn instance<G: Flop>(game: &mut G) -> &mut FlopInstance {
+    game.as_mut()
+}
+
+fn duration(secs: f64) -> Duration {
+    if !(secs > 0.0) {
+        return Duration::new(0, 0);
+    }
+    let whole = secs.min(u64::max_value() as f64).floor();
+    let nanos = ((secs - whole) * 1e9) as u32;
+    Duration::new(whole as u64, nanos.min(999_999_999))
+}
@@ -0,0 +1,416 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use event;
+use instance::FlopInstance;
+use item;
+use load_types::{self, LoadError, SourceProvider};
+use runtime;
+use symbol::{Interner, Symbol, SymbolMap};
+
+pub enum ReloadError {
+    Load(Vec<LoadError>),
+    // reloads can only happen between events
+    Running,
+    // the content was not loaded with a clone of the running symbols
+    Symbols,
+    Stranded(Vec<Stranded>),
+}
+
+/// Objects waiting somewhere that the new code no longer has.
+pub struct Stranded {
+    pub type_name: String,
+    pub algorithm_name: String,
+    // `None` if the whole algorithm is gone
+    pub wait: Option<String>,
+    pub count: usize,
+}
+
+/// What a reload changed.
+pub struct Reload {
+    // algorithms that were added, removed or edited, as `Type.name`
+    pub changed: Vec<String>,
+    // how many waiting objects resume at a different pc than before
+    pub moved: usize,
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReloadError::Load(ref errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n\n")?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            },
+            ReloadError::Running => {
+                write!(f, "cannot reload while an algorithm is running")
+            },
+            ReloadError::Symbols => {
+                write!(
+                    f,
+                    "content must be loaded with a clone of the running \
+                     symbols",
+                )
+            },
+            ReloadError::Stranded(ref stranded) => {
+                write!(f, "objects would be left waiting in removed code")?;
+                for each in stranded {
+                    write!(f, "\n    {}", each)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl fmt::Debug for ReloadError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Stranded {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}: {} object(s) waiting",
+            self.type_name,
+            self.algorithm_name,
+            self.count,
+        )?;
+        match self.wait {
+            Some(ref wait) => {
+                write!(f, " at {}, which the new code lacks", wait)
+            },
+            None => write!(f, ", but the algorithm is gone"),
+        }
+    }
+}
+
+impl fmt::Display for Reload {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.changed.len() == 0 {
+            write!(f, "no algorithms changed")?;
+        } else {
+            write!(f, "changed {}", self.changed.join(", "))?;
+        }
+        write!(f, "; {} waiting object(s) moved", self.moved)
+    }
+}
+
+impl FlopInstance {
+    /// Loads a content directory again and swaps it in, see `replace`.
+    pub fn reload(
+        self: &mut Self,
+        data_dir: &str,
+    ) -> Result<Reload, ReloadError> {
+        self.reload_from(&mut load_types::Directory::new(data_dir))
+    }
+
+    /// Loads everything a provider has and swaps it in, see `replace`.
+    pub fn reload_from<P: SourceProvider + ?Sized>(
+        self: &mut Self,
+        provider: &mut P,
+    ) -> Result<Reload, ReloadError> {
+        if self.call_stack.len() > 0 {
+            return Err(ReloadError::Running);
+        }
+        let symbols = self.symbols.clone();
+        let content = load_types::load(provider, symbols)
+            .map_err(ReloadError::Load)?;
+        self.replace(content)
+    }
+
+    /// Swaps in new content, moving every waiting object to the same wait
+    /// in the new code.
+    ///
+    /// The content must have been loaded with a clone of `symbols`, so that
+    /// the symbols held by objects and events still mean the same thing,
+    /// and is refused otherwise.
+    pub fn replace(
+        self: &mut Self,
+        content: load_types::Content,
+    ) -> Result<Reload, ReloadError> {
+        if self.call_stack.len() > 0 {
+            return Err(ReloadError::Running);
+        }
+        if !content.symbols.extends(&self.symbols) {
+            return Err(ReloadError::Symbols);
+        }
+
+        let mut events = Vec::new();
+        let mut stranded = BTreeMap::new();
+        let mut moved = 0;
+        for (time, queue_id, event) in self.pending_events() {
+            let type_name = event.object.borrow(&self.totem).type_name;
+            let pc = resume_pc(
+                &self.types,
+                &content.types,
+                &content.symbols,
+                type_name,
+                event,
+            );
+            match pc {
+                Ok(pc) => {
+                    if pc != event.pc {
+                        moved += 1;
+                    }
+                    let event = event::Event { pc, ..event.clone() };
+                    events.push((time, queue_id, event));
+                },
+                Err(wait) => {
+                    let place = (type_name, event.action_name, wait);
+                    *stranded.entry(place).or_insert(0) += 1;
+                },
+            }
+        }
+
+        // nothing is replaced unless every waiting object has a place
+        if stranded.len() > 0 {
+            let symbols = &content.symbols;
+            let stranded = stranded
+                .into_iter()
+                .map(|((type_name, algorithm_name, wait), count)| Stranded {
+                    type_name: symbols.name(type_name).into(),
+                    algorithm_name: symbols.name(algorithm_name).into(),
+                    wait,
+                    count,
+                })
+                .collect();
+            return Err(ReloadError::Stranded(stranded));
+        }
+
+        let changed = changed_algorithms(
+            &self.types,
+            &content.types,
+            &content.symbols,
+        );
+        let now = self.event_queue.now();
+        self.requeue(now, events);
+        let load_types::Content { types, roles, symbols } = content;
+        self.types = types;
+        self.roles = roles;
+        self.symbols = symbols;
+
+        Ok(Reload { changed, moved })
+    }
+}
+
+// where a waiting object resumes in the new types, or if it can't, which
+// wait it was in, or `None` if its algorithm is gone altogether
+fn resume_pc(
+    old: &SymbolMap<item::ObjectType>,
+    new: &SymbolMap<item::ObjectType>,
+    symbols: &Interner,
+    type_name: Symbol,
+    event: &event::Event,
+) -> Result<usize, Option<String>> {
+    let new_alg = find_algorithm(new, type_name, event.action_name)
+        .ok_or(None)?;
+    let old_alg = find_algorithm(old, type_name, event.action_name)
+        .ok_or_else(|| Some(format!("pc {}", event.pc)))?;
+    let label = old_alg.wait_label(event.pc)
+        .ok_or_else(|| Some(format!("pc {}", event.pc)))?;
+    let pc = new_alg.resume_pc(label)
+        .ok_or_else(|| Some(describe(symbols, label)))?;
+
+    // waits with no state are told apart only by their order, which any
+    // edit before them might have changed
+    let unchanged = pc == event.pc
+        && old_alg.code[..pc] == new_alg.code[..pc];
+    if label.state.is_none() && !unchanged {
+        return Err(Some(describe(symbols, label)));
+    }
+    Ok(pc)
+}
+
+fn describe(symbols: &Interner, label: runtime::WaitLabel) -> String {
+    match label.state {
+        Some(state) if label.index == 0 => {
+            format!("the wait after state {}", symbols.name(state))
+        },
+        Some(state) => format!(
+            "wait {} after state {}",
+            label.index + 1,
+            symbols.name(state),
+        ),
+        None => format!("wait {} with no state before it", label.index + 1),
+    }
+}
+
+fn changed_algorithms(
+    old: &SymbolMap<item::ObjectType>,
+    new: &SymbolMap<item::ObjectType>,
+    symbols: &Interner,
+) -> Vec<String> {
+    let mut names = Vec::new();
+    for types in &[old, new] {
+        for (&type_name, object_type) in types.iter() {
+            for &name in object_type.algorithm_ids.keys() {
+                names.push((type_name, name));
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+
+    let mut changed: Vec<String> = names
+        .into_iter()
+        .filter(|&(type_name, name)| {
+            let old_alg = find_algorithm(old, type_name, name);
+            let new_alg = find_algorithm(new, type_name, name);
+            old_alg.map(|alg| &alg.code) != new_alg.map(|alg| &alg.code)
+        })
+        .map(|(type_name, name)| {
+            format!("{}.{}", symbols.name(type_name), symbols.name(name))
+        })
+        .collect();
+    changed.sort();
+    changed
+}
+
+fn find_algorithm<'a>(
+    types: &'a SymbolMap<item::ObjectType>,
+    type_name: Symbol,
+    name: Symbol,
+) -> Option<&'a runtime::Algorithm> {
+    types.get(&type_name)
+        .and_then(|object_type| object_type.algorithm(name))
+}
+
+/// Notices changes to the files in a content directory, by polling their
+/// modification times.
+pub struct Watcher {
+    dir: PathBuf,
+    interval: Duration,
+    last_check: Instant,
+    stamps: Vec<(PathBuf, Option<SystemTime>, u64)>,
+}
+
+impl Watcher {
+    /// Watches `dir`, looking at it at most once every `interval`.
+    pub fn new<P: AsRef<Path>>(dir: P, interval: Duration) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        let stamps = stamps(&dir);
+        let last_check = Instant::now();
+        Watcher { dir, interval, last_check, stamps }
+    }
+
+    /// True if a file was added, removed or written since this last
+    /// returned true.
+    pub fn changed(self: &mut Self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let stamps = stamps(&self.dir);
+        if stamps == self.stamps {
+            return false;
+        }
+        self.stamps = stamps;
+        true
+    }
+}
+
+// every file under a directory with its modification time and size, in a
+// consistent order
+fn stamps(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let mut stamps = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else {
+                stamps.push((path, metadata.modified().ok(), metadata.len()));
+            }
+        }
+    }
+    stamps.sort();
+    stamps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use instance::test_host::Host;
+
+    // prints 1, 2 and 3 with a wait between each, neither of them after
+    // a state
+    const WAITS: &str = "
+        class Root {
+            state Done {}
+
+            ctor init() {
+                self.data = Done {};
+                print(1);
+                wait(1);
+                print(2);
+                wait(2);
+                print(3);
+            }
+        }
+    ";
+
+    // a root waiting in its first wait
+    fn waiting() -> Host {
+        let mut sources = vec![("Root.flop", WAITS)];
+        let content = load_types::load(&mut sources, Interner::new())
+            .unwrap();
+        let mut host = Host::new(content);
+        let _root = FlopInstance::start(
+            &mut host,
+            "Root".into(),
+            "init".into(),
+            Vec::new(),
+        ).unwrap();
+        host
+    }
+
+    #[test]
+    fn waits_with_no_state_keep_their_place() {
+        let mut host = waiting();
+        let edited = WAITS.replace("print(3)", "print(4)");
+        let mut sources = vec![("Root.flop", edited)];
+        assert!(host.instance.reload_from(&mut sources).is_ok());
+        FlopInstance::step(&mut host, 2);
+        assert_eq!(host.printed(), vec!["1", "2", "4"]);
+
+        // with the waits swapped, the first wait is another one
+        let mut host = waiting();
+        let swapped = WAITS
+            .replace("wait(1)", "wait(0)")
+            .replace("wait(2)", "wait(1)")
+            .replace("wait(0)", "wait(2)");
+        let mut sources = vec![("Root.flop", swapped)];
+        match host.instance.reload_from(&mut sources) {
+            Err(ReloadError::Stranded(ref stranded)) => {
+                assert_eq!(stranded.len(), 1);
+                assert_eq!(
+                    stranded[0].to_string(),
+                    "Root.init: 1 object(s) waiting at wait 1 with no \
+                     state before it, which the new code lacks",
+                );
+            },
+            Err(error) => panic!("{}", error),
+            Ok(_) => panic!("resumed in a reordered wait"),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use prelude::*;
+
+use ast;
+use data;
+use instance::FlopInstance;
+use load_types;
+use parser;
+use runtime;
+use symbol::{Interner, SymbolMap};
+use trivia;
+
+const HELP: &str = "\
+anything not starting with ':' is run as flop code, e.g.
+    x = Looper.run();
+    1 + 2
+commands:
+    :help           print this message
+    :vars           list variables
+    :show [NAME]    show the state of an object, or of the scratch object
+    :step [N]       run the next N events (default 1)
+    :until TIME     run every event due by TIME
+    :events         list pending events
+    :reload         load every class again; waiting objects keep their wait
+    :quit           leave";
+
+const SCRATCH_TYPE: &str = "<repl>";
+
+/// Runs lines of code on a scratch object, whose variables carry over from
+/// one line to the next.
+pub struct Repl {
+    data_dir: String,
+    game: FlopInstance,
+    scratch: data::Object,
+    vars: data::Data,
+}
+
+impl Repl {
+    pub fn new(data_dir: String, mut game: FlopInstance) -> Self {
+        let type_name = game.symbols.intern(SCRATCH_TYPE);
+        let scratch = data::ObjectData::new(type_name);
+        let vars = data::Data::new();
+        Repl { data_dir, game, scratch, vars }
+    }
+
+    /// Reads lines until the input ends or `:quit` is entered.
+    ///
+    /// Lines are joined up while braces are left open, so that blocks can be
+    /// spread over several lines.
+    pub fn run<R: BufRead>(self: &mut Self, input: R) {
+        let mut lines = input.lines();
+        let mut source = String::new();
+        loop {
+            let prompt = if source.len() == 0 { "> " } else { "... " };
+            print!("{}", prompt);
+            let _ = io::stdout().flush();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+            source.push_str(&line);
+            source.push('\n');
+
+            let depth = source.matches('{').count() as isize
+                - source.matches('}').count() as isize;
+            if depth > 0 {
+                continue;
+            }
+            let keep_going = self.handle(&source);
+            source.clear();
+            if !keep_going {
+                break;
+            }
+        }
+    }
+
+    /// Handles one complete entry, returning false if it asked to quit.
+    pub fn handle(self: &mut Self, source: &str) -> bool {
+        let trimmed = source.trim();
+        if trimmed.len() == 0 {
+            true
+        } else if trimmed.starts_with(":") {
+            self.command(&trimmed[1..])
+        } else {
+            self.evaluate(trimmed);
+            true
+        }
+    }
+
+    fn command(self: &mut Self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let arg = words.next();
+        match name {
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return false,
+            "vars" => {
+                let totem = &self.game.totem;
+                let symbols = &self.game.symbols;
+                for (name, value) in data::sorted_fields(symbols, &self.vars) {
+                    let value = data::show_field(totem, symbols, value);
+                    println!("{} = {}", name, value);
+                }
+            },
+            "show" => self.show(arg),
+            "step" => {
+                let count = match arg.map(str::parse) {
+                    None => 1,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => {
+                        println!("expected a number of events");
+                        return true;
+                    },
+                };
+                let ran = FlopInstance::step(&mut self.game, count);
+                self.report_time(ran);
+            },
+            "until" => {
+                let time = arg.and_then(|arg| arg.parse().ok())
+                    .and_then(|time| Time::try_from(time).ok());
+                match time {
+                    Some(time) => {
+                        let ran = FlopInstance::run_until(&mut self.game, time);
+                        self.report_time(ran);
+                    },
+                    None => println!("expected a time"),
+                }
+            },
+            "events" => self.list_events(),
+            "reload" => match self.game.reload(&self.data_dir) {
+                Ok(reload) => {
+                    println!("reloaded {}: {}", self.data_dir, reload);
+                },
+                Err(err) => println!("{}", err),
+            },
+            _ => println!("unknown command ':{}', try :help", name),
+        }
+        true
+    }
+
+    fn evaluate(self: &mut Self, source: &str) {
+        let alg = match compile(source, &mut self.game.symbols) {
+            Ok(alg) => alg,
+            Err(message) => {
+                println!("{}", message);
+                return;
+            },
+        };
+        let result = runtime::execute_snippet(
+            &mut self.game,
+            &self.scratch,
+            &alg,
+            &mut self.vars,
+        );
+        match result {
+            Ok(results) => {
+                for field in &results {
+                    let totem = &self.game.totem;
+                    let symbols = &self.game.symbols;
+                    println!("{}", data::show_field(totem, symbols, field));
+                }
+            },
+            Err(err) => println!("Error: {}", err),
+        }
+    }
+
+    fn show(self: &Self, name: Option<&str>) {
+        let totem = &self.game.totem;
+        let symbols = &self.game.symbols;
+        let object = match name {
+            None => &self.scratch,
+            Some(name) => match symbols.get(name)
+                .and_then(|name| self.vars.get(&name))
+            {
+                Some(&data::Field::TRef(ref object)) => object,
+                Some(&data::Field::VRef(ref object)) => &object.data,
+                Some(field) => {
+                    let value = data::show_field(totem, symbols, field);
+                    println!("{} = {}", name, value);
+                    return;
+                },
+                None => {
+                    println!("unknown variable '{}'", name);
+                    return;
+                },
+            },
+        };
+
+        let waiting = self.game.waiting.get(&data::address(object));
+        let object = object.borrow(totem);
+        println!(
+            "{} in state {}",
+            symbols.name(object.type_name),
+            symbols.name(object.state_name),
+        );
+        for (name, value) in data::sorted_fields(symbols, &object.data) {
+            let value = data::show_field(totem, symbols, value);
+            println!("    {} = {}", name, value);
+        }
+        if let (Some(event), &Some(ref handle)) = (waiting, &object.event) {
+            println!(
+                "    waiting in {} until {}",
+                symbols.name(event.action_name),
+                f64::from(handle.0),
+            );
+        }
+    }
+
+    fn list_events(self: &Self) {
+        let events = self.game.pending_events();
+        if events.len() == 0 {
+            println!("no pending events");
+        }
+        let symbols = &self.game.symbols;
+        for (time, _, event) in events {
+            let object = event.object.borrow(&self.game.totem);
+            println!(
+                "{:>10}  {}.{} @ pc {}",
+                f64::from(time),
+                symbols.name(object.type_name),
+                symbols.name(event.action_name),
+                event.pc,
+            );
+        }
+    }
+
+    fn report_time(self: &Self, ran: usize) {
+        println!(
+            "ran {} event(s), now {}",
+            ran,
+            f64::from(self.game.now()),
+        );
+    }
+}
+
+// an expression is printed, anything else is run as statements
+fn compile(
+    source: &str,
+    symbols: &mut Interner,
+) -> Result<runtime::Algorithm, String> {
+    let code = match trivia::strip(source) {
+        Ok((code, _)) => code,
+        Err(offset) => {
+            let err = load_types::unterminated_comment(source, offset);
+            return Err(format!("{}\n{}", err.message, err.snippet));
+        },
+    };
+    let steps = match parser::ExpressionParser::new().parse(&code) {
+        // builtins like `print(x)` only work as statements
+        Ok(ast::Expression::Method { ref names, .. }) if names.len() == 1 => {
+            parse_statements(source, &code)?
+        },
+        Ok(expression) => {
+            let expression = ast::Expression::Method {
+                names: vec!["return".into()],
+                args: vec![expression],
+            };
+            let expressions = vec![expression];
+            let results = Vec::new();
+            vec![(0, ast::Statement::Evaluate { expressions, results })]
+        },
+        Err(_) => parse_statements(source, &code)?,
+    };
+
+    let alg = ast::Algorithm {
+        offset: 0,
+        param_list: Vec::new(),
+        param_types: Vec::new(),
+        return_types: None,
+        steps,
+    };
+    let type_name: Rc<str> = Rc::from(SCRATCH_TYPE);
+    let lines = ast::LineIndex::new(source);
+    let context = ast::Context {
+        type_name: &type_name,
+        source_name: &type_name,
+        lines: &lines,
+        algorithms: &SymbolMap::new(),
+    };
+    let alg = ast::convert_algorithm(alg, SCRATCH_TYPE, &context, symbols)
+        .map_err(|err| err.message)?;
+
+    let waits = alg.code.iter().any(|op| match *op {
+        runtime::Op::Wait => true,
+        _ => false,
+    });
+    if waits {
+        return Err("wait can only be used inside a class".into());
+    }
+    Ok(alg)
+}
+
+// `code` is `source` with its comments blanked out
+fn parse_statements(source: &str, code: &str) -> Result<ast::Block, String> {
+    let parser = parser::SnippetParser::new();
+    let err = match parser.parse(code) {
+        Ok(steps) => return Ok(steps),
+        Err(err) => load_types::syntax_error(source, err),
+    };
+    // forgive a missing semicolon on the last statement
+    let code = code.trim_end();
+    if !code.ends_with(";") && !code.ends_with("}") {
+        let code = format!("{};", code);
+        if let Ok(steps) = parser.parse(&code) {
+            return Ok(steps);
+        }
+    }
+    Err(format!("{}\n{}", err.message, err.snippet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> String {
+        match compile(source, &mut Interner::new()) {
+            Ok(_) => panic!("{} compiled", source),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn bad_snippets_are_errors() {
+        assert_eq!(error("Foo()"), "Foo(..) expects 1 argument, got 0");
+        assert_eq!(error("wait(1, 2);"), "wait expects 1 argument");
+        assert_eq!(error("trace(1);"), "trace expects no arguments");
+        assert_eq!(error("a.b.c.d();"), "'a.b.c.d' is too long a path");
+        assert_eq!(
+            error("= 1, 2;"),
+            "separate statements with ';', not ','",
+        );
+        assert_eq!(error("wait(1);"), "wait can only be used inside a class");
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+
+use prelude::*;
+
+use item;
+use item::AlgorithmKind;
+use runtime;
+use symbol::Interner;
+
+pub struct ConformanceError {
+    pub type_name: String,
+    pub interface_name: String,
+    pub kind: RoleError,
+}
+
+pub enum RoleError {
+    UnknownRole {
+        role_type: Option<String>,
+        role_name: String,
+    },
+    Unimplemented {
+        method: String,
+    },
+    NotInRole {
+        method: String,
+    },
+    UnknownAlgorithm {
+        method: String,
+        algorithm: String,
+    },
+    WrongKind {
+        method: String,
+        algorithm: String,
+        expected: AlgorithmKind,
+    },
+    WrongParamCount {
+        method: String,
+        algorithm: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for AlgorithmKind {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AlgorithmKind::Constructor => write!(f, "ctor"),
+            AlgorithmKind::Function => write!(f, "fun"),
+        }
+    }
+}
+
+impl fmt::Display for ConformanceError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "interface {}.{}: ", self.type_name, self.interface_name)?;
+        match self.kind {
+            RoleError::UnknownRole { ref role_type, ref role_name } => {
+                match *role_type {
+                    Some(ref role_type) => write!(
+                        f,
+                        "unknown role '{}.{}'",
+                        role_type,
+                        role_name,
+                    ),
+                    None => write!(f, "unknown role '{}'", role_name),
+                }
+            },
+            RoleError::Unimplemented { ref method } => {
+                write!(f, "role method '{}' is not implemented", method)
+            },
+            RoleError::NotInRole { ref method } => {
+                write!(f, "'{}' is not a method of the role", method)
+            },
+            RoleError::UnknownAlgorithm { ref method, ref algorithm } => {
+                write!(
+                    f,
+                    "'{}' is implemented by '{}', which does not exist",
+                    method,
+                    algorithm,
+                )
+            },
+            RoleError::WrongKind { ref method, ref algorithm, expected } => {
+                write!(
+                    f,
+                    "'{}' must be a {}, but '{}' is not",
+                    method,
+                    expected,
+                    algorithm,
+                )
+            },
+            RoleError::WrongParamCount {
+                ref method,
+                ref algorithm,
+                expected,
+                found,
+            } => {
+                write!(
+                    f,
+                    "'{}' takes {} parameter(s), but '{}' takes {}",
+                    method,
+                    expected,
+                    algorithm,
+                    found,
+                )
+            },
+        }
+    }
+}
+
+// what a role asks of each method: its kind and parameter count
+type Signature = Dict<(AlgorithmKind, usize)>;
+
+/// Checks every interface of every type against the role it claims to
+/// implement.
+pub fn check_roles(
+    types: &Dict<item::ObjectType>,
+    roles: &Dict<item::Role>,
+    symbols: &Interner,
+) -> Vec<ConformanceError> {
+    let mut errors = Vec::new();
+
+    let mut type_names: Vec<&String> = types.keys().collect();
+    type_names.sort();
+    for type_name in type_names {
+        let object_type = &types[type_name];
+
+        let mut interface_names: Vec<&String> = object_type.interfaces
+            .keys()
+            .collect();
+        interface_names.sort();
+        for interface_name in interface_names {
+            let interface = &object_type.interfaces[interface_name];
+            let mut report = |kind| errors.push(ConformanceError {
+                type_name: type_name.clone(),
+                interface_name: interface_name.clone(),
+                kind,
+            });
+            check_interface(
+                types,
+                roles,
+                symbols,
+                object_type,
+                type_name,
+                interface_name,
+                interface,
+                &mut report,
+            );
+        }
+    }
+
+    errors
+}
+
+fn check_interface<F: FnMut(RoleError)>(
+    types: &Dict<item::ObjectType>,
+    roles: &Dict<item::Role>,
+    symbols: &Interner,
+    object_type: &item::ObjectType,
+    type_name: &String,
+    interface_name: &String,
+    interface: &item::Interface,
+    report: &mut F,
+) {
+    let signature = match role_signature(
+        types,
+        roles,
+        symbols,
+        type_name,
+        interface_name,
+        interface,
+    ) {
+        Ok(signature) => signature,
+        Err(err) => {
+            report(err);
+            return;
+        },
+    };
+
+    let mut methods: Vec<&String> = interface.implementors.keys().collect();
+    methods.sort();
+    for method in methods {
+        let algorithm = &interface.implementors[method];
+        let (kind, param_count) = match (
+            object_type.kinds.get(algorithm),
+            algorithm_by_name(symbols, object_type, algorithm),
+        ) {
+            (Some(&kind), Some(alg)) => (kind, alg.param_count),
+            _ => {
+                report(RoleError::UnknownAlgorithm {
+                    method: method.clone(),
+                    algorithm: algorithm.clone(),
+                });
+                continue;
+            },
+        };
+
+        let signature = match signature {
+            Some(ref signature) => signature,
+            None => continue,
+        };
+        let &(expected_kind, expected_count) = match signature.get(method) {
+            Some(term) => term,
+            None => {
+                report(RoleError::NotInRole { method: method.clone() });
+                continue;
+            },
+        };
+        if kind != expected_kind {
+            report(RoleError::WrongKind {
+                method: method.clone(),
+                algorithm: algorithm.clone(),
+                expected: expected_kind,
+            });
+        } else if param_count != expected_count {
+            report(RoleError::WrongParamCount {
+                method: method.clone(),
+                algorithm: algorithm.clone(),
+                expected: expected_count,
+                found: param_count,
+            });
+        }
+    }
+
+    if let Some(signature) = signature {
+        let mut terms: Vec<&String> = signature.keys().collect();
+        terms.sort();
+        for term in terms {
+            if !interface.implementors.contains_key(term) {
+                report(RoleError::Unimplemented { method: term.clone() });
+            }
+        }
+    }
+}
+
+// `Ok(None)` means the interface has an implicit role of its own, which it
+// trivially satisfies
+fn role_signature(
+    types: &Dict<item::ObjectType>,
+    roles: &Dict<item::Role>,
+    symbols: &Interner,
+    type_name: &String,
+    interface_name: &String,
+    interface: &item::Interface,
+) -> Result<Option<Signature>, RoleError> {
+    let unknown = || RoleError::UnknownRole {
+        role_type: interface.role_type.clone(),
+        role_name: interface.role_name.clone(),
+    };
+
+    let owner_name = interface.role_type.as_ref().unwrap_or(type_name);
+    let owner = types.get(owner_name).ok_or_else(&unknown)?;
+
+    // roles declared in the class itself shadow global ones
+    if let Some(role) = owner.roles.get(&interface.role_name) {
+        return Ok(Some(role_term_signature(role)));
+    }
+
+    if interface.role_type.is_none() {
+        if let Some(role) = roles.get(&interface.role_name) {
+            return Ok(Some(role_term_signature(role)));
+        }
+        if &interface.role_name == interface_name {
+            return Ok(None);
+        }
+        return Err(unknown());
+    }
+
+    // implementing another type's interface means implementing its implicit
+    // role
+    let other = owner.interfaces.get(&interface.role_name)
+        .ok_or_else(&unknown)?;
+    let mut signature = Dict::with_capacity(other.implementors.len());
+    for (method, algorithm) in &other.implementors {
+        let kind = owner.kinds.get(algorithm);
+        let alg = algorithm_by_name(symbols, owner, algorithm);
+        if let (Some(&kind), Some(alg)) = (kind, alg) {
+            signature.insert(method.clone(), (kind, alg.param_count));
+        }
+    }
+    Ok(Some(signature))
+}
+
+fn role_term_signature(role: &item::Role) -> Signature {
+    role.iter()
+        .map(|(name, term)| {
+            let sig = match *term {
+                item::RoleTerm::Constructor(ref params) => {
+                    (AlgorithmKind::Constructor, params.len())
+                },
+                item::RoleTerm::Function(ref params) => {
+                    (AlgorithmKind::Function, params.len())
+                },
+            };
+            (name.clone(), sig)
+        })
+        .collect()
+}
+
+fn algorithm_by_name<'a>(
+    symbols: &Interner,
+    object_type: &'a item::ObjectType,
+    name: &str,
+) -> Option<&'a runtime::Algorithm> {
+    symbols.get(name).and_then(|name| object_type.algorithm(name))
+}
@@ -1,185 +1,384 @@
+use std::fmt;
 use std::mem;
+use std::rc::Rc;
 
 use prelude::*;
 
 use ast;
 use data;
+use error::FlopError;
 use event;
 use item;
+use symbol::{self, Interner, Symbol, SymbolMap};
+use trace;
 
-use instance::FlopParts;
+use instance::{FlopInstance, FlopParts};
 
+/// An algorithm compiled to a flat list of ops for a stack machine.
+///
+/// Expressions push their values onto the stack, and anything that takes a
+/// variable number of values, like a call, takes everything above the last
+/// `Op::Mark`. Local variables live in numbered slots, parameters first.
 pub struct Algorithm {
-    pub param_list: Vec<String>,
-    pub steps: Vec<Statement>,
+    pub name: Rc<str>,
+    pub type_name: Rc<str>,
+    // the same names, interned
+    pub symbol: Symbol,
+    pub type_symbol: Symbol,
+    pub param_count: usize,
+    // the name of each slot
+    pub locals: Vec<Symbol>,
+    pub code: Vec<Op>,
+    // source line of each op
+    pub lines: Vec<usize>,
+    // whether each op is the first of a statement
+    pub starts: Vec<bool>,
+    // the pc of each `Op::Wait`, with its label
+    pub waits: Vec<(usize, WaitLabel)>,
+    pub source_name: Rc<str>,
 }
 
-#[derive(Clone)]
+/// Names a wait in a way that survives edits to the rest of its algorithm:
+/// by the state literal assigned right before it, if any, and by how many
+/// earlier waits in the algorithm have the same state. Waits with no state
+/// are only found again if the code before them is unchanged.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WaitLabel {
+    pub state: Option<Symbol>,
+    pub index: usize,
+}
+
+/// The variables of a running algorithm.
+#[derive(Clone, Copy)]
+pub struct Locals<'a> {
+    names: &'a [Symbol],
+    values: &'a [Option<data::Field>],
+}
+
+/// One entry in the flop call stack, for error traces and debug dumps.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub type_name: Rc<str>,
+    pub algorithm_name: Rc<str>,
+    pub source_name: Rc<str>,
+    pub pc: usize,
+    pub line: usize,
+    // true if this algorithm was resumed from a wait by the event queue
+    pub resumed: bool,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{} @ pc {} ({}:{})",
+            self.type_name,
+            self.algorithm_name,
+            self.pc,
+            self.source_name,
+            self.line,
+        )?;
+        if self.resumed {
+            write!(f, " [resumed by event]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Something a script printed.
+pub enum Output<'a> {
+    // a fixed message, like the one `BANG;` prints
+    Message(&'a str),
+    Values(&'a [data::Field]),
+    // the call stack at a `trace()`, outermost frame first
+    Trace(&'a [Frame]),
+}
+
+#[derive(Clone, PartialEq)]
 pub enum TablePath {
     Virtual(String),
     Static(String, String),
 }
 
-#[derive(Clone)]
-pub enum Statement {
+#[derive(Clone, PartialEq)]
+pub enum Op {
+    // statements
     Debug(String),
-    DebugNums(Vec<Expression>),
-    // TODO self.method() and Type.initializer stuff
-    Evaluate {
-        // multiple expressions all so that x, y = y, x is possible :P
-        expressions: Vec<Expression>,
-        results: Vec<String>,
-    },
-    State(Expression),
-    Wait(Expression),
-    Return(Vec<Expression>),
-    Branch {
-        condition: Expression,
-        break_offset: usize,
-    },
-    PatternBranch {
-        data: Expression,
-        arms: Dict<(Vec<String>, usize)>,
-        default_offset: usize,
-    },
-    Continue(usize),
+    // prints the marked values
+    Print,
+    Trace,
+    // pops the marked values into slots, dropping any left over
+    Store(Vec<usize>),
+    State,
+    Wait,
+    Return,
+    // pops a condition, and jumps if it is false
+    Branch(usize),
     Jump(usize),
-}
-
-#[derive(Clone)]
-pub enum Expression {
-    Var(String),
-    InitObject {
-        type_name: String,
-        table_name: String,
-        init_name: String,
-        args: Vec<Expression>,
-    },
-    ExecObject {
-        object_name: String,
-        action_name: String,
-        args: Vec<Expression>,
-    },
-    InitSet,
-    ExternCall {
-        function_name: String,
-        args: Vec<Expression>,
-    },
-    VirtualizeObject {
-        interface_name: String,
-        object: Box<Expression>,
+    // pops data, binds the fields of the matching arm to slots and jumps to
+    // it
+    Match {
+        arms: SymbolMap<(Vec<(Symbol, usize)>, usize)>,
+        default: usize,
     },
+
+    // expressions
+    Mark,
+    // drops the last mark, checking that exactly one value came after it
+    Single,
+    Load(usize),
+    Const(f64),
+    Bool(bool),
     SelfObject,
     SelfData,
-
+    InitSet,
+    // pops one value per field
     Data {
-        name: String,
-        fields: Dict<Expression>,
+        name: Symbol,
+        fields: Vec<Symbol>,
+    },
+    Virtualize(Symbol),
+    Not,
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    // the remainder, which is never negative, so that counting down wraps
+    Mod,
+    Pow,
+    Compare(ast::CompareOp),
+    // for all but the last link of a chain like `a < b < c`: leaves the
+    // right operand for the next link, or pushes false and jumps out
+    CompareAnd(ast::CompareOp, usize),
+    // for `and` and `or`: pops a condition, and if it is the one given,
+    // pushes it back and jumps past the right operand
+    ShortCircuit(bool, usize),
+
+    // calls, which take their arguments from the last mark
+    InitObject {
+        type_name: Symbol,
+        table_name: Symbol,
+        init_name: Symbol,
     },
+    InitConcrete {
+        type_name: Symbol,
+        init_name: Symbol,
+    },
+    // an algorithm of this type, by id, and by name in case the types have
+    // changed since
+    ExecSelf(usize, Symbol),
+    ExecSelfNamed(Symbol),
+    // a method of the object or set in a slot
+    ExecLocal {
+        slot: usize,
+        action_name: Symbol,
+    },
+    ExternCall(String),
+}
 
-    Const(f64),
-    Comparison(Box<Expression>, Vec<(ast::CompareOp, Expression)>),
-    Add(Box<Expression>, Box<Expression>),
-    Sub(Box<Expression>, Box<Expression>),
-    Mul(Box<Expression>, Box<Expression>),
-    Div(Box<Expression>, Box<Expression>),
-    Pow(Box<Expression>, Box<Expression>),
+impl<'a> Output<'a> {
+    /// Formats the output the way `FlopInstance` prints it.
+    pub fn show(self: &Self, totem: &Totem, symbols: &Interner) -> String {
+        match *self {
+            Output::Message(message) => message.into(),
+            Output::Values(values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| data::show_field(totem, symbols, value))
+                    .collect();
+                values.join(", ")
+            },
+            Output::Trace(frames) => {
+                let mut text = String::from("Trace:");
+                for frame in frames.iter().rev() {
+                    text.push_str(&format!("\n    {}", frame));
+                }
+                text
+            },
+        }
+    }
 }
 
 impl Algorithm {
-    fn bind_args(self: &Self, args: Vec<data::Field>) -> data::Data {
-        self.param_list
-            .iter()
+    /// Source line of the op at `pc`, or of the last op if the algorithm has
+    /// run off the end
+    pub fn line(self: &Self, pc: usize) -> usize {
+        self.lines.get(pc)
+            .or(self.lines.last())
             .cloned()
-            .zip(args)
-            .collect()
+            .unwrap_or(0)
+    }
+
+    /// The label of the wait that resumes at `pc`.
+    pub fn wait_label(self: &Self, pc: usize) -> Option<WaitLabel> {
+        self.waits
+            .iter()
+            .find(|&&(wait, _)| wait + 1 == pc)
+            .map(|&(_, label)| label)
+    }
+
+    /// Where an object resumes after the wait with the given label.
+    pub fn resume_pc(self: &Self, label: WaitLabel) -> Option<usize> {
+        self.waits
+            .iter()
+            .find(|&&(_, other)| other == label)
+            .map(|&(wait, _)| wait + 1)
+    }
+
+    /// The slot holding the variable `name`, if the algorithm uses one.
+    pub fn slot(self: &Self, name: Symbol) -> Option<usize> {
+        self.locals.iter().position(|&local| local == name)
+    }
+
+    fn bind_args(
+        self: &Self,
+        args: Vec<data::Field>,
+    ) -> Result<Vec<Option<data::Field>>, FlopError> {
+        // calls from the host are not type checked
+        if args.len() != self.param_count {
+            return Err(FlopError::WrongArgCount {
+                name: format!("{}.{}", self.type_name, self.name),
+                expected: self.param_count,
+                found: args.len(),
+            });
+        }
+        let mut slots = vec![None; self.locals.len()];
+        for (slot, arg) in slots.iter_mut().zip(args) {
+            *slot = Some(arg);
+        }
+        Ok(slots)
+    }
+
+    fn bind_data(self: &Self, data: data::Data) -> Vec<Option<data::Field>> {
+        let mut slots = vec![None; self.locals.len()];
+        for (name, value) in data {
+            if let Some(slot) = self.slot(name) {
+                slots[slot] = Some(value);
+            }
+        }
+        slots
+    }
+}
+
+impl<'a> Locals<'a> {
+    /// Every variable that has been given a value, in slot order.
+    pub fn iter(self: &Self) -> impl Iterator<Item=(Symbol, &'a data::Field)> {
+        self.names
+            .iter()
+            .zip(self.values)
+            .filter_map(|(&name, value)| {
+                value.as_ref().map(|value| (name, value))
+            })
+    }
+
+    pub fn get(self: &Self, name: Symbol) -> Option<&'a data::Field> {
+        self.iter()
+            .find(|&(local, _)| local == name)
+            .map(|(_, value)| value)
     }
 }
 
 /// Executes a constructor on a fresh object and wrap in a vref
 pub fn execute_ctor_virtual<G: Flop>(
     game: &mut G,
-    type_name: String,
-    interface_name: String,
-    init_name: String,
+    type_name: Symbol,
+    interface_name: Symbol,
+    init_name: Symbol,
     args: Vec<data::Field>,
-) -> data::ObjectRef {
-    let init_name = item::get_algorithm_name(
-        game.types(),
-        &type_name,
-        &interface_name,
-        &init_name,
-    ).clone();
-    let tref = execute_ctor_concrete(
+) -> Result<data::ObjectRef, FlopError> {
+    let alg = {
+        let instance = instance(game);
+        item::get_implementor(
+            &instance.types,
+            &instance.symbols,
+            type_name,
+            interface_name,
+            init_name,
+        )?
+    };
+    let tref = data::ObjectData::new(type_name);
+    execute_algorithm(
         game,
-        type_name,
-        init_name,
+        Strong::clone(&tref),
+        &alg,
 
-        args,
-    );
+        ExecType::Ctor(args),
+    )?;
 
     let table = interface_name;
     let data = tref;
-    data::ObjectRef { data, table }
+    Ok(data::ObjectRef { data, table })
 }
 
 /// Executes a constructor on a fresh object
 pub fn execute_ctor_concrete<G: Flop>(
     game: &mut G,
-    type_name: String,
-    init_name: String,
+    type_name: Symbol,
+    init_name: Symbol,
     args: Vec<data::Field>,
-) -> data::Object {
+) -> Result<data::Object, FlopError> {
+    let alg = {
+        let instance = instance(game);
+        item::get_algorithm(
+            &instance.types,
+            &instance.symbols,
+            type_name,
+            init_name,
+        )?
+    };
     let tref = data::ObjectData::new(type_name);
 
     execute_algorithm(
         game,
         Strong::clone(&tref),
-        init_name,
+        &alg,
 
         ExecType::Ctor(args),
-    );
+    )?;
 
-    tref
+    Ok(tref)
 }
 
 /// Executes a function on a vref
 pub fn execute_fun_virtual<G: Flop>(
     game: &mut G,
     vref: data::ObjectRef,
-    alg_name: String,
+    alg_name: Symbol,
     args: Vec<data::Field>,
-) -> Vec<data::Field> {
-    let alg_name = {
-        let (totem, types, _) = game.parts();
-        let type_name = &vref.data.borrow(totem).type_name;
-        item::get_algorithm_name(
-            types,
-            &type_name,
-            &vref.table,
-            &alg_name,
-        ).clone()
+) -> Result<Vec<data::Field>, FlopError> {
+    let alg = {
+        let instance = instance(game);
+        let type_name = vref.data.borrow(&instance.totem).type_name;
+        item::get_implementor(
+            &instance.types,
+            &instance.symbols,
+            type_name,
+            vref.table,
+            alg_name,
+        )?
     };
-    execute_fun_concrete(
+    execute_algorithm(
         game,
         vref.data,
-        alg_name,
+        &alg,
 
-        args,
+        ExecType::Fun(args),
     )
 }
 
-/// Executes a constructor on a fresh object
+/// Executes a function on a tref
 pub fn execute_fun_concrete<G: Flop>(
     game: &mut G,
     tref: data::Object,
-    alg_name: String,
+    alg_name: Symbol,
     args: Vec<data::Field>,
-) -> Vec<data::Field> {
+) -> Result<Vec<data::Field>, FlopError> {
+    let alg = find_algorithm(game, &tref, alg_name)?;
     execute_algorithm(
         game,
         tref,
-        alg_name,
+        &alg,
 
         ExecType::Fun(args),
     )
@@ -188,15 +387,59 @@ pub fn execute_fun_concrete<G: Flop>(
 pub fn resume_algorithm<G: Flop>(
     game: &mut G,
     object: data::Object,
-    alg_name: String,
+    alg_name: Symbol,
     pc: usize,
-) {
+) -> Result<(), FlopError> {
+    let alg = find_algorithm(game, &object, alg_name)?;
     execute_algorithm(
         game,
         object,
-        alg_name,
+        &alg,
         ExecType::Resume(pc),
+    )?;
+    Ok(())
+}
+
+/// Executes loose code on an object, such as a line typed into the repl,
+/// keeping its variables in `vars` afterwards
+pub fn execute_snippet<G: Flop>(
+    game: &mut G,
+    object: &data::Object,
+    alg: &Algorithm,
+    vars: &mut data::Data,
+) -> Result<Vec<data::Field>, FlopError> {
+    game.call_stack().push(Frame {
+        type_name: Rc::clone(&alg.type_name),
+        algorithm_name: Rc::clone(&alg.name),
+        source_name: Rc::clone(&alg.source_name),
+        pc: 0,
+        line: alg.line(0),
+        resumed: false,
+    });
+
+    let mut slots: Vec<_> = alg.locals
+        .iter()
+        .map(|name| vars.remove(name))
+        .collect();
+    let result = execute_code(
+        game,
+        object,
+        alg,
+
+        0,
+        &mut slots,
+        true,
     );
+    let result = result.map_err(|err| err.named(&instance(game).symbols));
+    for (&name, value) in alg.locals.iter().zip(slots) {
+        if let Some(value) = value {
+            vars.insert(name, value);
+        }
+    }
+
+    let result = result.map_err(|err| err.traced(game.call_stack()));
+    game.call_stack().pop();
+    result
 }
 
 enum ExecType {
@@ -209,200 +452,511 @@ fn execute_algorithm<G: Flop>(
     game: &mut G,
 
     object: Strong<data::ObjectData>,
-    algorithm_name: String,
+    alg: &Algorithm,
 
     input: ExecType,
-) -> Vec<data::Field> {
-    let mut result = None;
+) -> Result<Vec<data::Field>, FlopError> {
     let mut pc = 0;
     let mut has_state = false;
-    let mut vars;
-
-    let type_name = {
-        let object = object.borrow(game.totem());
-        object.type_name.clone()
-    };
-
-
-    let alg = &*item::get_algorithm(
-        game.types(),
-        &type_name,
-        &algorithm_name
-    );
+    let mut resumed = false;
 
     if let ExecType::Fun(_) = input { has_state = true; }
-    match input {
-        ExecType::Fun(args) | ExecType::Ctor(args) => {
-            vars = alg.bind_args(args);
-        },
+    if tracing(game) {
+        let name = trace::Value::Str(&alg.name);
+        match input {
+            ExecType::Ctor(ref args) => {
+                let args = trace::Value::Fields(args);
+                let extra = [("algorithm", name), ("args", args)];
+                record(game, &object, "construct", &extra);
+            },
+            ExecType::Fun(ref args) => {
+                let args = trace::Value::Fields(args);
+                let extra = [("algorithm", name), ("args", args)];
+                record(game, &object, "enter", &extra);
+            },
+            ExecType::Resume(pc) => {
+                let pc = trace::Value::Int(pc);
+                let extra = [("algorithm", name), ("pc", pc)];
+                record(game, &object, "resume", &extra);
+            },
+        }
+    }
+    let mut slots = match input {
+        ExecType::Fun(args) | ExecType::Ctor(args) => alg.bind_args(args)?,
         ExecType::Resume(pc_) => {
             pc = pc_;
-            vars = {
+            resumed = true;
+            let data = {
                 let object = object.borrow_mut(game.totem());
-                object.event.take().unwrap();
-
-                mem::replace(&mut object.data, Dict::new())
+                mem::replace(&mut object.data, data::Data::new())
             };
+            alg.bind_data(data)
         },
+    };
+
+    game.call_stack().push(Frame {
+        type_name: Rc::clone(&alg.type_name),
+        algorithm_name: Rc::clone(&alg.name),
+        source_name: Rc::clone(&alg.source_name),
+        pc,
+        line: alg.line(pc),
+        resumed,
+    });
+
+    let result = execute_code(
+        game,
+        &object,
+        alg,
+
+        pc,
+        &mut slots,
+        has_state,
+    );
+    let result = result.map_err(|err| err.named(&instance(game).symbols));
+    if tracing(game) {
+        let name = trace::Value::Str(&alg.name);
+        match result {
+            Ok(ref results) => {
+                let results = trace::Value::Fields(results);
+                record(game, &object, "exit", &[
+                    ("algorithm", name),
+                    ("results", results),
+                ]);
+            },
+            Err(ref err) => {
+                let message = err.cause().to_string();
+                let error = trace::Value::Str(&message);
+                record(game, &object, "exit", &[
+                    ("algorithm", name),
+                    ("error", error),
+                ]);
+            },
+        }
     }
 
-    let code = &alg.steps;
+    let result = result.map_err(|err| err.traced(game.call_stack()));
+    game.call_stack().pop();
+    result
+}
+
+fn execute_code<G: Flop>(
+    game: &mut G,
+
+    object: &data::Object,
+    alg: &Algorithm,
+
+    mut pc: usize,
+    slots: &mut Vec<Option<data::Field>>,
+    mut has_state: bool,
+) -> Result<Vec<data::Field>, FlopError> {
+    let mut result = None;
+    let mut stack = Vec::new();
+    let mut marks = Vec::new();
+
+    let code = &alg.code;
     while pc < code.len() {
-        if let Statement::Wait(time) = &code[pc] {
-            let time_ = evaluate_expression(
-                game,
-                time,
-                &mut vars,
-                &object,
-            ).num();
-            let time = Time::try_from(time_)
-                .expect("Num Error");
-            let (totem, _, event_queue) = game.parts();
-            wait(
-                totem,
-                event_queue,
-
-                &object,
-
-                algorithm_name,
-                pc,
-
-                time,
-            );
-
-            break;
-        } else if result.is_some() {
-            break;
-        } else { match code[pc] {
-            Statement::Wait(_) => {
-                unreachable!();
-            },
-            Statement::Debug(ref to_print) => {
-                println!("Debug: {}", to_print);
-            },
-            Statement::DebugNums(ref exprs) => {
-                let mut result = evaluate_expressions(
-                    game,
-                    exprs,
-                    &mut vars,
-                    &object,
-                ).into_iter();
-                print!("Debug: {}", result.next().unwrap().num());
-                for x in result {
-                    print!(", {}", x.num());
-                }
-                println!("");
+        if alg.starts[pc] {
+            if let Some(frame) = game.call_stack().last_mut() {
+                frame.pc = pc;
+                frame.line = alg.lines[pc];
+            }
+            let locals = Locals { names: &alg.locals, values: slots };
+            game.before_statement(object, locals);
+        }
+
+        match code[pc] {
+            Op::Debug(ref message) => {
+                let output = Output::Message(message);
+                game.output(object, &alg.name, output);
             },
-            Statement::Evaluate {
-                ref results,
-                ref expressions,
-            } => {
-                let result_vals = evaluate_expressions(
-                    game,
-                    expressions,
-                    &mut vars,
-                    &object,
-                );
-                for (name, val) in results.iter().zip(result_vals) {
-                    vars.insert(name.clone(), val);
+            Op::Print => {
+                let values = pop_marked(&mut stack, &mut marks);
+                let output = Output::Values(&values);
+                game.output(object, &alg.name, output);
+            },
+            Op::Trace => {
+                let frames = game.call_stack().clone();
+                let output = Output::Trace(&frames);
+                game.output(object, &alg.name, output);
+            },
+            Op::Store(ref targets) => {
+                let values = pop_marked(&mut stack, &mut marks);
+                for (&slot, value) in targets.iter().zip(values) {
+                    slots[slot] = Some(value);
                 }
             },
-            Statement::State(ref state) => {
-                let (state_name, data) = evaluate_expression(
-                    game,
-                    state,
-                    &mut vars,
-                    &object,
-                ).unwrap_data();
+            Op::State => {
+                let (state_name, data) = pop(&mut stack).unwrap_data()?;
 
-                let object = object.borrow_mut(game.totem());
+                game.waiting().remove(&data::address(object));
+                let (from, cancelled) = {
+                    let object = object.borrow_mut(game.totem());
 
-                let event = object.event.take();
-                if let Some(event::EventHandle(ref time, id)) = event {
-                    game.event_queue().cancel_event(time, id);
-                }
+                    let event = object.event.take();
+                    if let Some(event::EventHandle(ref time, id)) = event {
+                        game.event_queue().cancel_event(time, id);
+                    }
 
-                object.state_name = state_name;
-                object.data = data;
+                    let from = mem::replace(
+                        &mut object.state_name,
+                        state_name,
+                    );
+                    object.data = data;
+                    (from, event.map(|event::EventHandle(time, _)| time))
+                };
+
+                if tracing(game) {
+                    if let Some(at) = cancelled {
+                        let at = trace::Value::Num(f64::from(at));
+                        record(game, object, "cancel", &[("at", at)]);
+                    }
+                    let current = object.borrow(game.totem());
+                    record(game, object, "state", &[
+                        ("from", trace::Value::Symbol(from)),
+                        ("to", trace::Value::Symbol(current.state_name)),
+                        ("data", trace::Value::Data(&current.data)),
+                    ]);
+                }
 
                 has_state = true;
-            }
+            },
+            Op::Wait => {
+                let time_ = pop(&mut stack).num()?;
+                let time = Time::try_from(time_)
+                    .map_err(|_| FlopError::InvalidTime(time_))?;
+                let (totem, event_queue, waiting) = game.parts();
+                wait(
+                    totem,
+                    event_queue,
+                    waiting,
 
-            Statement::Return(ref vals) => {
-                let vals = evaluate_expressions(
-                    game,
-                    vals,
-                    &mut vars,
-                    &object,
+                    object,
+
+                    alg.symbol,
+                    pc,
+
+                    time,
                 );
-                result = Some(vals);
-            },
+                if tracing(game) {
+                    let at = match object.borrow(game.totem()).event {
+                        Some(event::EventHandle(time, _)) => f64::from(time),
+                        None => unreachable!(),
+                    };
+                    record(game, object, "wait", &[
+                        ("algorithm", trace::Value::Str(&alg.name)),
+                        ("pc", trace::Value::Int(pc + 1)),
+                        ("at", trace::Value::Num(at)),
+                    ]);
+                }
 
-            Statement::Branch {
-                ref condition,
-                break_offset,
-            } => {
-                let mut condition = evaluate_expression(
-                    game,
-                    condition,
-                    &mut vars,
-                    &object,
-                ).bool();
-                if !condition {
-                    pc += break_offset;
+                break;
+            },
+            Op::Return => {
+                result = Some(pop_marked(&mut stack, &mut marks));
+                break;
+            },
+            Op::Branch(target) => {
+                if !pop(&mut stack).bool()? {
+                    pc = target;
                     continue;
                 }
             },
-            Statement::PatternBranch {
-                ref data,
-                ref arms,
-                default_offset,
-            } => {
-                let (name, mut field_vals) = evaluate_expression(
-                    game,
-                    data,
-                    &mut vars,
-                    &object,
-                ).unwrap_data();
-                let mut offset = default_offset;
-                if let Some((fields, this_offset)) = arms.get(&name) {
-                    for field in fields {
-                        let val = field_vals.remove(field)
-                            .expect("field not found for match arm");
-                        vars.insert(field.clone(), val);
+            Op::Jump(target) => {
+                pc = target;
+                continue;
+            },
+            Op::Match { ref arms, default } => {
+                let (name, mut fields) = pop(&mut stack).unwrap_data()?;
+                pc = default;
+                if let Some(&(ref bindings, target)) = arms.get(&name) {
+                    for &(field, slot) in bindings {
+                        let value = match fields.remove(&field) {
+                            Some(value) => value,
+                            None => {
+                                let symbols = &instance(game).symbols;
+                                return Err(FlopError::MissingField {
+                                    variant: symbols.name(name).into(),
+                                    field: symbols.name(field).into(),
+                                });
+                            },
+                        };
+                        slots[slot] = Some(value);
                     }
-                    offset = *this_offset;
+                    pc = target;
                 }
-                pc += offset;
                 continue;
             },
-            Statement::Continue(break_offset) => {
-                pc -= break_offset;
-                continue;
+
+            Op::Mark => {
+                marks.push(stack.len());
             },
-            Statement::Jump(break_offset) => {
-                pc += break_offset;
-                continue;
+            Op::Single => {
+                let found = stack.len() - marks.pop().unwrap();
+                if found != 1 {
+                    return Err(FlopError::WrongResultCount {
+                        expected: 1,
+                        found,
+                    });
+                }
+            },
+            Op::Load(slot) => {
+                let value = match slots[slot] {
+                    Some(ref value) => value.clone(),
+                    None => {
+                        let name = alg.locals[slot];
+                        return Err(unknown_variable(game, name));
+                    },
+                };
+                stack.push(value);
+            },
+            Op::Const(x) => {
+                stack.push(data::Field::Num(x));
+            },
+            Op::Bool(x) => {
+                stack.push(data::Field::from_bool(x));
+            },
+            Op::SelfObject => {
+                stack.push(data::Field::TRef(Strong::clone(object)));
+            },
+            Op::SelfData => {
+                let obj = object.borrow(game.totem());
+                let name = obj.state_name;
+                let data = obj.data.clone();
+                stack.push(data::Field::Data(name, data));
+            },
+            Op::InitSet => {
+                stack.push(data::Field::Set(data::ObjectSet::new()));
+            },
+            Op::Data { name, ref fields } => {
+                let start = stack.len() - fields.len();
+                let data = fields
+                    .iter()
+                    .cloned()
+                    .zip(stack.drain(start..))
+                    .collect();
+                stack.push(data::Field::Data(name, data));
+            },
+            Op::Virtualize(interface_name) => {
+                let table = interface_name;
+                let data = pop(&mut stack).unwrap_tref()?;
+                let vref = data::ObjectRef { table, data };
+                stack.push(data::Field::VRef(vref));
+            },
+            Op::Not => {
+                let x = pop(&mut stack).bool()?;
+                stack.push(data::Field::from_bool(!x));
+            },
+            Op::Neg => {
+                let x = pop(&mut stack).num()?;
+                stack.push(data::Field::Num(-x));
+            },
+            Op::Add => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::Num(x + y));
+            },
+            Op::Sub => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::Num(x - y));
+            },
+            Op::Mul => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::Num(x * y));
+            },
+            Op::Div => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::Num(x / y));
+            },
+            Op::Mod => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::Num(x.rem_euclid(y)));
+            },
+            Op::Pow => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::Num(x.powf(y)));
+            },
+            Op::Compare(ref op) => {
+                let (x, y) = pop_nums(&mut stack)?;
+                stack.push(data::Field::from_bool(compare(op, x, y)));
+            },
+            Op::CompareAnd(ref op, target) => {
+                let (x, y) = pop_nums(&mut stack)?;
+                if !compare(op, x, y) {
+                    stack.push(data::Field::from_bool(false));
+                    pc = target;
+                    continue;
+                }
+                stack.push(data::Field::Num(y));
             },
-        }}
+            Op::ShortCircuit(decides, target) => {
+                if pop(&mut stack).bool()? == decides {
+                    stack.push(data::Field::from_bool(decides));
+                    pc = target;
+                    continue;
+                }
+            },
+
+            Op::InitObject {
+                type_name,
+                table_name,
+                init_name,
+            } => {
+                let args = pop_marked(&mut stack, &mut marks);
+                let vref = execute_ctor_virtual(
+                    game,
+                    type_name,
+                    table_name,
+                    init_name,
+                    args,
+                )?;
+                stack.push(data::Field::VRef(vref));
+            },
+            Op::InitConcrete { type_name, init_name } => {
+                let args = pop_marked(&mut stack, &mut marks);
+                let tref = execute_ctor_concrete(
+                    game,
+                    type_name,
+                    init_name,
+                    args,
+                )?;
+                stack.push(data::Field::TRef(tref));
+            },
+            Op::ExecSelf(id, action_name) => {
+                let args = pop_marked(&mut stack, &mut marks);
+                let callee = {
+                    let instance = instance(game);
+                    item::get_algorithm_by_id(
+                        &instance.types,
+                        &instance.symbols,
+                        alg.type_symbol,
+                        id,
+                        action_name,
+                    )?
+                };
+                let results = execute_algorithm(
+                    game,
+                    Strong::clone(object),
+                    &callee,
+                    ExecType::Fun(args),
+                )?;
+                stack.extend(results);
+            },
+            Op::ExecSelfNamed(action_name) => {
+                let args = pop_marked(&mut stack, &mut marks);
+                let results = execute_fun_concrete(
+                    game,
+                    Strong::clone(object),
+                    action_name,
+                    args,
+                )?;
+                stack.extend(results);
+            },
+            Op::ExecLocal { slot, action_name } => {
+                let args = pop_marked(&mut stack, &mut marks);
+                let results = execute_local(
+                    game,
+                    alg.locals[slot],
+                    &mut slots[slot],
+                    action_name,
+                    args,
+                )?;
+                stack.extend(results);
+            },
+            Op::ExternCall(ref function_name) => {
+                let args = pop_marked(&mut stack, &mut marks);
+                let traced_args = if tracing(game) {
+                    Some(args.clone())
+                } else {
+                    None
+                };
+                let results = game.extern_call(function_name, args)?;
+                if let Some(args) = traced_args {
+                    record(game, object, "extern", &[
+                        ("function", trace::Value::Str(function_name)),
+                        ("args", trace::Value::Fields(&args)),
+                        ("results", trace::Value::Fields(&results)),
+                    ]);
+                }
+                stack.extend(results);
+            },
+        }
 
         pc += 1;
     }
 
     if !has_state {
-        panic!("Tried to exit without resetting state");
+        return Err(FlopError::ExitWithoutState {
+            type_name: alg.type_name.to_string(),
+            algorithm_name: alg.name.to_string(),
+        });
     }
 
-    result.unwrap_or_else(|| Vec::new())
+    Ok(result.unwrap_or_else(|| Vec::new()))
+}
+
+// calls a method on a variable, which sets handle themselves
+fn execute_local<G: Flop>(
+    game: &mut G,
+    name: Symbol,
+    value: &mut Option<data::Field>,
+    action_name: Symbol,
+    mut args: Vec<data::Field>,
+) -> Result<Vec<data::Field>, FlopError> {
+    use data::Field::*;
+    let value = match *value {
+        Some(ref mut value) => value,
+        None => return Err(unknown_variable(game, name)),
+    };
+    let mut result = Vec::new();
+    match *value {
+        Set(ref mut x) => {
+            if action_name == symbol::ADD {
+                check_arg_count("Set.add", &args, 1)?;
+                let object = args.pop().unwrap().unwrap_vref()?;
+                x.insert(object);
+            } else if action_name == symbol::REMOVE {
+                check_arg_count("Set.remove", &args, 1)?;
+                let object = args.pop().unwrap().unwrap_vref()?;
+                x.remove(&object);
+            } else if action_name == symbol::NEXT {
+                check_arg_count("Set.next", &args, 0)?;
+                let val = x.pop().ok_or(FlopError::EmptySet)?;
+                result.push(data::Field::VRef(val));
+            } else if action_name == symbol::NOT_EMPTY {
+                // TODO !set.is_empty()
+                check_arg_count("Set.not_empty", &args, 0)?;
+                result.push(data::Field::from_bool(!x.is_empty()));
+            } else {
+                let name = instance(game).symbols.name(action_name).into();
+                return Err(FlopError::UnknownSetMethod(name));
+            }
+        },
+        TRef(ref tref) => {
+            let tref = Strong::clone(tref);
+            result = execute_fun_concrete(game, tref, action_name, args)?;
+        },
+        VRef(ref vref) => {
+            let vref = vref.clone();
+            result = execute_fun_virtual(game, vref, action_name, args)?;
+        },
+        ref other => {
+            let found = other.describe(&instance(game).symbols);
+            return Err(FlopError::TypeMismatch {
+                expected: "an object",
+                found,
+            });
+        },
+    }
+    Ok(result)
 }
 
 fn wait(
     totem: &mut Totem,
     event_queue: &mut event::EventQueue,
+    waiting: &mut event::Waiting,
 
     object_: &data::Object,
 
-    action_name: String,
+    action_name: Symbol,
     mut pc: usize,
 
     time: Time,
@@ -413,345 +967,101 @@ fn wait(
     let event = event::Event { object, action_name, pc };
 
     let absolute_time = event_queue.now() + time;
-    let id = event_queue.enqueue_absolute(event, absolute_time);
+    let id = event_queue.enqueue_absolute(event.clone(), absolute_time);
+    waiting.insert(data::address(object_), absolute_time, id, event);
 
     let object = object_.borrow_mut(totem);
 
     object.event = Some(event::EventHandle(absolute_time, id));
 }
 
-fn evaluate_expression<G: Flop>(
-    game: &mut G,
-    expression: &Expression,
-    vars: &mut data::Data,
-    object: &data::Object,
-) -> data::Field {
-    let mut result = Vec::new();
-
-    evaluate_expression_into(
-        game,
-        expression,
-        vars,
-        object,
-        &mut result,
-    );
+// the compiler makes sure that ops never pop more than was pushed
+fn pop(stack: &mut Vec<data::Field>) -> data::Field {
+    stack.pop().unwrap()
+}
 
-    assert!(result.len() == 1, "Expected single result");
-    result.pop().unwrap()
+fn pop_nums(stack: &mut Vec<data::Field>) -> Result<(f64, f64), FlopError> {
+    let y = pop(stack).num()?;
+    let x = pop(stack).num()?;
+    Ok((x, y))
 }
 
-fn evaluate_expressions<G: Flop>(
-    game: &mut G,
-    expressions: &Vec<Expression>,
-    vars: &mut data::Data,
-    object: &data::Object,
+fn pop_marked(
+    stack: &mut Vec<data::Field>,
+    marks: &mut Vec<usize>,
 ) -> Vec<data::Field> {
-    let mut result = Vec::new();
+    let start = marks.pop().unwrap();
+    stack.split_off(start)
+}
 
-    for expression in expressions {
-        evaluate_expression_into(
-            game,
-            expression,
-            vars,
-            object,
-            &mut result,
-        );
+fn compare(op: &ast::CompareOp, x: f64, y: f64) -> bool {
+    use ast::CompareOp::*;
+    match *op {
+        Equals => x == y,
+        NEquals => x != y,
+        LessEq => x <= y,
+        GreaterEq => x >= y,
+        Less => x < y,
+        Greater => x > y,
     }
+}
 
-    result
+fn instance<G: Flop>(game: &mut G) -> &mut FlopInstance {
+    game.as_mut()
 }
 
-fn evaluate_expression_into<G: Flop>(
+// looks up an algorithm of an object's own type
+fn find_algorithm<G: Flop>(
     game: &mut G,
-    expression: &Expression,
-    vars: &mut data::Data,
     object: &data::Object,
-    result: &mut Vec<data::Field>,
-) {
-    use self::Expression::*;
-    match *expression {
-        Var(ref name) => {
-            let val = vars[name].clone();
-            result.push(val);
-        },
-        InitObject {
-            ref type_name,
-            ref table_name,
-            ref init_name,
-            ref args,
-        } => {
-            let args = evaluate_expressions(
-                game,
-                args,
-                vars,
-                object,
-            );
-            let vref = execute_ctor_virtual(
-                game,
-                type_name.clone(),
-                table_name.clone(),
-                init_name.clone(),
-                args
-            );
-
-            let result_term = data::Field::VRef(vref);
-            result.push(result_term);
-        },
-        ExecObject {
-            ref object_name,
-            ref action_name,
-            ref args,
-        } => {
-            let mut args = evaluate_expressions(
-                game,
-                args,
-                vars,
-                &object,
-            );
-
-            use data::Field::*;
-            if object_name != "self" {
-                if !vars.contains_key(object_name) {
-                    let type_name = object_name;
-
-                    let tref = execute_ctor_concrete(
-                        game,
-                        type_name.clone(),
-                        action_name.clone(),
-                        args
-                    );
-
-                    let result_term = data::Field::TRef(tref);
-                    result.push(result_term);
-                    return;
-                }
-                if let Set(x) = vars.get_mut(object_name).unwrap() {
-                    if action_name == "add" {
-                        assert!(args.len() == 1, "Set.add expects one arg");
-                        let key = args.pop().unwrap().unwrap_vref();
-                        x.insert(data::ObjectKey(key), ());
-                    } else if action_name == "remove" {
-                        assert!(args.len() == 1, "Set.remove expects one arg");
-                        let key = args.pop().unwrap().unwrap_vref();
-                        x.remove(&data::ObjectKey(key));
-                    } else if action_name == "next" {
-                        assert!(args.len() == 0, "Set.next expects no args");
-                        let (val, ()) = data::set_pop(x)
-                            .expect("Cannot remove from empty set");
-                        result.push(data::Field::VRef(val));
-                    } else if action_name == "not_empty" {
-                        // TODO !set.is_empty()
-                        assert!(args.len() == 0, "Set.not_empty expects no args");
-                        result.push(data::Field::from_bool(!x.is_empty()));
-                    }
-                    // return so that we can continue in a scope where vars still
-                    // exists
-                    return;
-                }
-            }
-
-
-            let result_vals;
-            if object_name == "self" {
-                result_vals = execute_fun_concrete(
-                    game,
-                    object.clone(),
-                    action_name.clone(),
-                    args,
-                );
-            } else { match vars[object_name].clone() {
-                TRef(tref) => {
-                    result_vals = execute_fun_concrete(
-                        game,
-                        tref,
-                        action_name.clone(),
-                        args,
-                    );
-                },
-                VRef(vref) => {
-                    result_vals = execute_fun_virtual(
-                        game,
-                        vref,
-                        action_name.clone(),
-                        args,
-                    )
-                },
-                _ => {
-                    panic!("Method called on simple data");
-                },
-            }}
-
-            result.extend(result_vals);
-        },
-        InitSet => {
-            result.push(data::Field::Set(data::ObjectSet::new()));
-        },
-
-        ExternCall { ref function_name, ref args } => {
-            let args = evaluate_expressions(
-                game,
-                args,
-                vars,
-                object,
-            );
-
-            let results = game.extern_call(function_name, args);
-
-            result.extend(results);
-        },
+    alg_name: Symbol,
+) -> Result<Rc<Algorithm>, FlopError> {
+    let instance = instance(game);
+    let type_name = object.borrow(&instance.totem).type_name;
+    item::get_algorithm(
+        &instance.types,
+        &instance.symbols,
+        type_name,
+        alg_name,
+    )
+}
 
-        VirtualizeObject { ref interface_name, object: ref tref_expr } => {
-            let mut tref = evaluate_expression(
-                game,
-                tref_expr,
-                vars,
-                &object,
-            );
-            let table = interface_name.clone();
-            let data = tref.unwrap_tref();
-            let vref = data::ObjectRef { table, data };
-            result.push(data::Field::VRef(vref));
-        },
+fn unknown_variable<G: Flop>(game: &mut G, name: Symbol) -> FlopError {
+    let name = instance(game).symbols.name(name).into();
+    FlopError::UnknownVariable(name)
+}
 
-        SelfObject => {
-            result.push(data::Field::TRef(Strong::clone(object)));
-        },
-        SelfData => {
-            let obj = object.borrow(&game.totem());
-            let name = obj.state_name.clone();
-            let data = obj.data.clone();
-            result.push(data::Field::Data(name, data));
-        },
+fn tracing<G: Flop>(game: &mut G) -> bool {
+    let instance: &mut FlopInstance = game.as_mut();
+    instance.tracer.is_some()
+}
 
-        Data { ref name, ref fields } => {
-            // TODO make this another kind of eval function?
-            // might make errors even worse
-            let mut eval = |expr|
-                evaluate_expression(
-                    game,
-                    expr,
-                    vars,
-                    object,
-                );
-            let data = fields
-                 .iter()
-                 .map(|(fname, val)| (fname.clone(), eval(val)))
-                 .collect();
-            result.push(data::Field::Data(name.clone(), data));
-        },
+// writes a trace record, if a tracer is set
+fn record<G: Flop>(
+    game: &mut G,
+    object: &data::Object,
+    kind: &str,
+    extra: &[(&str, trace::Value)],
+) {
+    let instance: &mut FlopInstance = game.as_mut();
+    if let Some(ref mut tracer) = instance.tracer {
+        let now = instance.event_queue.now();
+        let symbols = &instance.symbols;
+        tracer.record(&instance.totem, symbols, now, object, kind, extra);
+    }
+}
 
-        Const(x) => {
-            result.push(data::Field::Num(x));
-        },
-        Comparison(ref x, ref ys) => {
-            let mut x = evaluate_expression(
-                game,
-                &**x,
-                vars,
-                object,
-            ).num();
-            for (ref op, ref y) in ys {
-                let y = evaluate_expression(
-                    game,
-                    y,
-                    vars,
-                    object,
-                ).num();
-                use ast::CompareOp::*;
-                let succeeded = match op {
-                    Equals => x == y,
-                    NEquals => x != y,
-                    LessEq => x <= y,
-                    GreaterEq => x >= y,
-                    Less => x < y,
-                    Greater => x > y,
-                };
-                if !succeeded {
-                    result.push(data::Field::from_bool(false));
-                    return;
-                }
-                x = y;
-            }
-            result.push(data::Field::from_bool(true));
-        },
-        Add(ref x, ref y) => {
-            let mut x = evaluate_expression(
-                game,
-                &**x,
-                vars,
-                object,
-            ).num();
-            let mut y = evaluate_expression(
-                game,
-                &**y,
-                vars,
-                object,
-            ).num();
-            result.push(data::Field::Num(x + y));
-        },
-        Sub(ref x, ref y) => {
-            let mut x = evaluate_expression(
-                game,
-                &**x,
-                vars,
-                object,
-            ).num();
-            let mut y = evaluate_expression(
-                game,
-                &**y,
-                vars,
-                object,
-            ).num();
-
-            result.push(data::Field::Num(x - y));
-        },
-        Mul(ref x, ref y) => {
-            let mut x = evaluate_expression(
-                game,
-                &**x,
-                vars,
-                object,
-            ).num();
-            let mut y = evaluate_expression(
-                game,
-                &**y,
-                vars,
-                object,
-            ).num();
-
-            result.push(data::Field::Num(x * y));
-        },
-        Div(ref x, ref y) => {
-            let mut x = evaluate_expression(
-                game,
-                &**x,
-                vars,
-                object,
-            ).num();
-            let mut y = evaluate_expression(
-                game,
-                &**y,
-                vars,
-                object,
-            ).num();
-
-            result.push(data::Field::Num(x / y));
-        },
-        Pow(ref x, ref y) => {
-            let mut x = evaluate_expression(
-                game,
-                &**x,
-                vars,
-                object,
-            ).num();
-            let mut y = evaluate_expression(
-                game,
-                &**y,
-                vars,
-                object,
-            ).num();
-
-            result.push(data::Field::Num(x.powf(y)));
-        },
+fn check_arg_count(
+    name: &str,
+    args: &Vec<data::Field>,
+    expected: usize,
+) -> Result<(), FlopError> {
+    if args.len() != expected {
+        return Err(FlopError::WrongArgCount {
+            name: name.into(),
+            expected,
+            found: args.len(),
+        });
     }
+    Ok(())
 }
@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use prelude::*;
+
+use data;
+use event;
+use instance::FlopInstance;
+use symbol::{self, Interner, Symbol};
+
+// a save is whitespace separated tokens:
+//
+//     flop-save 1
+//     now <time>
+//     object <id> <type> <state>
+//     event <time> <queue id> <algorithm> <pc>
+//     field <name> <value>
+//     end
+//     root <id>
+//
+// where objects are numbered in the order they are written, and a value is
+// `num <number>`, `tref <id>`, `vref <interface> <id>`,
+// `data <variant> <count> (<name> <value>)*` or
+// `set <count> (<interface> <id>)*`
+const HEADER: &str = "flop-save";
+const VERSION: u32 = 1;
+
+pub enum SaveError {
+    Io(io::Error),
+    // saves can only be taken between events
+    Running,
+    Format {
+        line: usize,
+        message: String,
+    },
+}
+
+impl From<io::Error> for SaveError {
+    fn from(err: io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveError::Io(ref err) => write!(f, "{}", err),
+            SaveError::Running => {
+                write!(f, "cannot save while an algorithm is running")
+            },
+            SaveError::Format { line, ref message } => {
+                write!(f, "line {}: {}", line, message)
+            },
+        }
+    }
+}
+
+impl fmt::Debug for SaveError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FlopInstance {
+    /// Writes every object reachable from `roots` or from the event queue.
+    pub fn save<W: Write>(
+        self: &Self,
+        roots: &[data::Object],
+        out: &mut W,
+    ) -> Result<(), SaveError> {
+        if self.call_stack.len() > 0 {
+            return Err(SaveError::Running);
+        }
+
+        let mut objects = Numbering::new();
+        for root in roots {
+            objects.number(root);
+        }
+        let events = self.pending_events();
+        for &(_, _, event) in &events {
+            objects.number(&event.object);
+        }
+
+        writeln!(out, "{} {}", HEADER, VERSION)?;
+        writeln!(out, "now {:?}", f64::from(self.event_queue.now()))?;
+
+        // objects get numbered as they are found, so this grows as it goes
+        let mut id = 0;
+        while id < objects.order.len() {
+            let object = Strong::clone(&objects.order[id]);
+            let object = object.borrow(&self.totem);
+            writeln!(
+                out,
+                "object {} {} {}",
+                id,
+                self.symbols.name(object.type_name),
+                self.symbols.name(object.state_name),
+            )?;
+
+            let address = data::address(&objects.order[id]);
+            let event = self.waiting.get(&address);
+            if let (&Some(ref handle), Some(event)) = (&object.event, event) {
+                let event::EventHandle(time, queue_id) = *handle;
+                writeln!(
+                    out,
+                    "event {:?} {} {} {}",
+                    f64::from(time),
+                    queue_id,
+                    self.symbols.name(event.action_name),
+                    event.pc,
+                )?;
+            }
+
+            let fields = data::sorted_fields(&self.symbols, &object.data);
+            for (name, value) in fields {
+                write!(out, "field {} ", name)?;
+                write_value(out, &self.symbols, value, &mut objects)?;
+                writeln!(out)?;
+            }
+            writeln!(out, "end")?;
+            id += 1;
+        }
+
+        for root in roots {
+            writeln!(out, "root {}", objects.number(root))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the event queue with the one in a save, returning the roots
+    /// that were passed to `save`.
+    ///
+    /// Types are not part of a save, so this should be called on a fresh
+    /// instance with the same content loaded.
+    pub fn restore<R: Read>(
+        self: &mut Self,
+        input: &mut R,
+    ) -> Result<Vec<data::Object>, SaveError> {
+        if self.call_stack.len() > 0 {
+            return Err(SaveError::Running);
+        }
+
+        let mut text = String::new();
+        input.read_to_string(&mut text)?;
+        let mut reader = Reader::new(&text, &mut self.symbols);
+
+        reader.expect(HEADER)?;
+        let version: u32 = reader.parse()?;
+        if version != VERSION {
+            return Err(reader.error(format!(
+                "unsupported save version {}",
+                version,
+            )));
+        }
+        reader.expect("now")?;
+        let now = reader.time()?;
+
+        let mut events = Vec::new();
+        let mut roots = Vec::new();
+        while let Some(word) = reader.next() {
+            match word {
+                "object" => {
+                    let id = reader.id()?;
+                    if reader.declared[id] {
+                        return Err(reader.error(format!(
+                            "object {} is declared twice",
+                            id,
+                        )));
+                    }
+                    reader.declared[id] = true;
+                    let object = reader.object(id);
+                    let type_name = reader.symbol()?;
+                    let state_name = reader.symbol()?;
+                    if !self.types.contains_key(&type_name) {
+                        return Err(reader.error(format!(
+                            "unknown type '{}'",
+                            reader.name(type_name),
+                        )));
+                    }
+
+                    let mut fields = data::Data::new();
+                    loop {
+                        match reader.word()? {
+                            "event" => {
+                                let time = reader.time()?;
+                                if time < now {
+                                    return Err(reader.error(format!(
+                                        "event at {} is before now, {}",
+                                        f64::from(time),
+                                        f64::from(now),
+                                    )));
+                                }
+                                let queue_id: usize = reader.parse()?;
+                                let action_name = reader.symbol()?;
+                                let pc: usize = reader.parse()?;
+                                let object_type = &self.types[&type_name];
+                                let alg = match object_type
+                                    .algorithm(action_name)
+                                {
+                                    Some(alg) => alg,
+                                    None => return Err(reader.error(format!(
+                                        "type '{}' has no algorithm '{}'",
+                                        reader.name(type_name),
+                                        reader.name(action_name),
+                                    ))),
+                                };
+                                // waits always resume right after themselves
+                                if alg.wait_label(pc).is_none() {
+                                    return Err(reader.error(format!(
+                                        "{}.{} can't resume at pc {}",
+                                        reader.name(type_name),
+                                        reader.name(action_name),
+                                        pc,
+                                    )));
+                                }
+                                let event = event::Event {
+                                    object: Strong::clone(&object),
+                                    action_name,
+                                    pc,
+                                };
+                                events.push((time, queue_id, event));
+                            },
+                            "field" => {
+                                let name = reader.symbol()?;
+                                let value = reader.value()?;
+                                fields.insert(name, value);
+                            },
+                            "end" => break,
+                            word => {
+                                return Err(reader.error(format!(
+                                    "unexpected '{}'",
+                                    word,
+                                )));
+                            },
+                        }
+                    }
+
+                    let object = object.borrow_mut(&mut self.totem);
+                    object.type_name = type_name;
+                    object.state_name = state_name;
+                    object.data = fields;
+                },
+                "root" => {
+                    let id = reader.id()?;
+                    roots.push(reader.object(id));
+                },
+                word => {
+                    return Err(reader.error(format!(
+                        "unexpected '{}'",
+                        word,
+                    )));
+                },
+            }
+        }
+        if let Some(id) = reader.declared.iter().position(|&done| !done) {
+            return Err(reader.error(format!(
+                "object {} is used but never declared",
+                id,
+            )));
+        }
+
+        // nothing is replaced until the whole save has been read
+        self.requeue(now, events);
+
+        Ok(roots)
+    }
+}
+
+// assigns save ids to objects in the order they are first seen
+struct Numbering {
+    ids: HashMap<usize, usize>,
+    order: Vec<data::Object>,
+}
+
+impl Numbering {
+    fn new() -> Self {
+        Numbering { ids: HashMap::new(), order: Vec::new() }
+    }
+
+    fn number(self: &mut Self, object: &data::Object) -> usize {
+        let order = &mut self.order;
+        *self.ids.entry(data::address(object)).or_insert_with(|| {
+            order.push(Strong::clone(object));
+            order.len() - 1
+        })
+    }
+}
+
+fn write_value<W: Write>(
+    out: &mut W,
+    symbols: &Interner,
+    value: &data::Field,
+    objects: &mut Numbering,
+) -> io::Result<()> {
+    match *value {
+        data::Field::Num(num) => write!(out, "num {:?}", num),
+        data::Field::TRef(ref object) => {
+            write!(out, "tref {}", objects.number(object))
+        },
+        data::Field::VRef(ref object) => {
+            let id = objects.number(&object.data);
+            write!(out, "vref {} {}", symbols.name(object.table), id)
+        },
+        data::Field::Data(variant, ref fields) => {
+            let variant = symbols.name(variant);
+            write!(out, "data {} {}", variant, fields.len())?;
+            for (name, value) in data::sorted_fields(symbols, fields) {
+                write!(out, " {} ", name)?;
+                write_value(out, symbols, value, objects)?;
+            }
+            Ok(())
+        },
+        data::Field::Set(ref set) => {
+            write!(out, "set {}", set.len())?;
+            for object in set.iter() {
+                let id = objects.number(&object.data);
+                write!(out, " {} {}", symbols.name(object.table), id)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+struct Reader<'a> {
+    // each token along with its line number
+    tokens: Vec<(usize, &'a str)>,
+    pos: usize,
+    objects: Vec<data::Object>,
+    declared: Vec<bool>,
+    symbols: &'a mut Interner,
+}
+
+impl<'a> Reader<'a> {
+    fn new(text: &'a str, symbols: &'a mut Interner) -> Self {
+        let tokens = text.lines()
+            .enumerate()
+            .flat_map(|(i, line)| {
+                line.split_whitespace().map(move |token| (i + 1, token))
+            })
+            .collect();
+        Reader {
+            tokens,
+            pos: 0,
+            objects: Vec::new(),
+            declared: Vec::new(),
+            symbols,
+        }
+    }
+
+    fn error(self: &Self, message: String) -> SaveError {
+        let line = match self.tokens.get(self.pos.max(1) - 1) {
+            Some(&(line, _)) => line,
+            None => 0,
+        };
+        SaveError::Format { line, message }
+    }
+
+    fn next(self: &mut Self) -> Option<&'a str> {
+        let token = self.tokens.get(self.pos).map(|&(_, token)| token);
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn word(self: &mut Self) -> Result<&'a str, SaveError> {
+        self.next()
+            .ok_or_else(|| self.error("unexpected end of save".into()))
+    }
+
+    fn symbol(self: &mut Self) -> Result<Symbol, SaveError> {
+        let word = self.word()?;
+        Ok(self.symbols.intern(word))
+    }
+
+    fn name(self: &Self, symbol: Symbol) -> &str {
+        self.symbols.name(symbol)
+    }
+
+    fn expect(self: &mut Self, expected: &str) -> Result<(), SaveError> {
+        let word = self.word()?;
+        if word != expected {
+            return Err(self.error(format!(
+                "expected '{}', found '{}'",
+                expected,
+                word,
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse<T: FromStr>(self: &mut Self) -> Result<T, SaveError> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| self.error(format!("invalid number '{}'", word)))
+    }
+
+    fn time(self: &mut Self) -> Result<Time, SaveError> {
+        let time: f64 = self.parse()?;
+        Time::try_from(time)
+            .map_err(|_| self.error(format!("invalid time {}", time)))
+    }
+
+    fn id(self: &mut Self) -> Result<usize, SaveError> {
+        let id: usize = self.parse()?;
+        // every object takes up more than one token, which bounds the ids
+        if id >= self.tokens.len() {
+            return Err(self.error(format!("object id {} is too large", id)));
+        }
+        while self.objects.len() <= id {
+            self.objects.push(data::ObjectData::new(symbol::EMPTY));
+            self.declared.push(false);
+        }
+        Ok(id)
+    }
+
+    fn object(self: &Self, id: usize) -> data::Object {
+        Strong::clone(&self.objects[id])
+    }
+
+    fn object_ref(self: &mut Self) -> Result<data::ObjectRef, SaveError> {
+        let table = self.symbol()?;
+        let id = self.id()?;
+        Ok(data::ObjectRef { table, data: self.object(id) })
+    }
+
+    fn value(self: &mut Self) -> Result<data::Field, SaveError> {
+        match self.word()? {
+            "num" => Ok(data::Field::Num(self.parse()?)),
+            "tref" => {
+                let id = self.id()?;
+                Ok(data::Field::TRef(self.object(id)))
+            },
+            "vref" => Ok(data::Field::VRef(self.object_ref()?)),
+            "data" => {
+                let variant = self.symbol()?;
+                let count: usize = self.parse()?;
+                let mut fields = data::Data::new();
+                for _ in 0..count {
+                    let name = self.symbol()?;
+                    let value = self.value()?;
+                    fields.insert(name, value);
+                }
+                Ok(data::Field::Data(variant, fields))
+            },
+            "set" => {
+                let count: usize = self.parse()?;
+                let mut set = data::ObjectSet::new();
+                for _ in 0..count {
+                    set.insert(self.object_ref()?);
+                }
+                Ok(data::Field::Set(set))
+            },
+            word => Err(self.error(format!("unknown value '{}'", word))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use instance::FlopInstance;
+    use instance::test_host::Host;
+    use load_types;
+    use symbol::Interner;
+
+    const ITEM: &str = "
+        class Item {
+            state Made { id: Num }
+            interface Item { id }
+
+            ctor make(id: Num) {
+                self.data = Made { id = id };
+            }
+
+            fun id() -> Num {
+                match self.data {
+                    Made { id } => { return(id); }
+                }
+            }
+        }
+    ";
+
+    // picks one item out of a set per event, keeping the set in its state,
+    // whose fields are the locals again after each wait
+    const ROOT: &str = "
+        class Root {
+            state Picking { items: Set }
+            state Done {}
+
+            ctor init() {
+                items = Set.new();
+                i = 0;
+                while i < 12 {
+                    items.add(Item(Item.make(i)));
+                    i = i + 1;
+                }
+                while items.not_empty() {
+                    item = items.next();
+                    print(item.id());
+                    self.data = Picking { items = items };
+                    wait(1);
+                }
+                self.data = Done {};
+            }
+        }
+    ";
+
+    fn host() -> Host {
+        let mut sources = vec![("Item.flop", ITEM), ("Root.flop", ROOT)];
+        let content = load_types::load(&mut sources, Interner::new())
+            .unwrap();
+        Host::new(content)
+    }
+
+    fn start(host: &mut Host) -> data::Object {
+        let root = FlopInstance::start(
+            host,
+            "Root".into(),
+            "init".into(),
+            Vec::new(),
+        );
+        root.unwrap()
+    }
+
+    #[test]
+    fn restored_run_matches_uninterrupted_run() {
+        let mut whole = host();
+        let _root = start(&mut whole);
+        FlopInstance::step(&mut whole, 20);
+        assert_eq!(whole.printed().len(), 12);
+
+        let mut before = host();
+        let root = start(&mut before);
+        FlopInstance::step(&mut before, 5);
+        let mut saved = Vec::new();
+        before.instance.save(&[root], &mut saved).unwrap();
+
+        let mut after = host();
+        let _roots = after.instance.restore(&mut &saved[..]).unwrap();
+        FlopInstance::step(&mut after, 20);
+
+        let mut printed = before.printed();
+        printed.extend(after.printed());
+        assert_eq!(printed, whole.printed());
+    }
+
+    #[test]
+    fn events_in_the_past_are_refused() {
+        let mut before = host();
+        let root = start(&mut before);
+        FlopInstance::step(&mut before, 5);
+        let mut saved = Vec::new();
+        before.instance.save(&[root], &mut saved).unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(saved.contains("\nnow 5.0\n"));
+
+        let later = saved.replace("\nnow 5.0\n", "\nnow 7.0\n");
+        let mut after = host();
+        match after.instance.restore(&mut later.as_bytes()) {
+            Err(error) => {
+                assert!(error.to_string().contains("event at 6 is before"));
+            },
+            Ok(_) => panic!("restored an event from before now"),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct Symbol(u32);
+
+pub type SymbolMap<T> = HashMap<Symbol, T>;
+
+// names the runtime needs without an interner at hand, in the order every
+// interner starts with them
+const RESERVED: [&str; 7] = [
+    "True",
+    "False",
+    "EMPTY",
+    "add",
+    "remove",
+    "next",
+    "not_empty",
+];
+
+pub const TRUE: Symbol = Symbol(0);
+pub const FALSE: Symbol = Symbol(1);
+// the state of an object whose constructor hasn't set one yet
+pub const EMPTY: Symbol = Symbol(2);
+pub const ADD: Symbol = Symbol(3);
+pub const REMOVE: Symbol = Symbol(4);
+pub const NEXT: Symbol = Symbol(5);
+pub const NOT_EMPTY: Symbol = Symbol(6);
+
+/// Turns names into symbols and back. Interners only grow, so a clone
+/// agrees with the original about every symbol the original made.
+#[derive(Clone)]
+pub struct Interner {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, Symbol>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        let mut interner = Interner {
+            names: Vec::new(),
+            ids: HashMap::new(),
+        };
+        for name in RESERVED.iter() {
+            interner.intern(name);
+        }
+        interner
+    }
+
+    pub fn intern(self: &mut Self, name: &str) -> Symbol {
+        if let Some(&symbol) = self.ids.get(name) {
+            return symbol;
+        }
+        let symbol = Symbol(self.names.len() as u32);
+        let name: Rc<str> = Rc::from(name);
+        self.names.push(Rc::clone(&name));
+        self.ids.insert(name, symbol);
+        symbol
+    }
+
+    /// The symbol for `name`, if it has ever been interned.
+    pub fn get(self: &Self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).cloned()
+    }
+
+    pub fn name(self: &Self, symbol: Symbol) -> &str {
+        &self.names[symbol.0 as usize]
+    }
+
+    /// True if every symbol of `other` means the same thing here, as it does
+    /// in a clone of `other` that has had more names added.
+    pub fn extends(self: &Self, other: &Interner) -> bool {
+        self.names.len() >= other.names.len()
+            && other.names.iter().zip(&self.names).all(|(x, y)| x == y)
+    }
+}
+
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use prelude::*;
+
+use data;
+use symbol::{Interner, Symbol};
+
+pub(crate) enum Value<'a> {
+    Str(&'a str),
+    Symbol(Symbol),
+    Int(usize),
+    Num(f64),
+    Fields(&'a [data::Field]),
+    Data(&'a data::Data),
+}
+
+// what's needed to turn objects and symbols into names
+struct Names<'a> {
+    totem: &'a Totem,
+    symbols: &'a Interner,
+}
+
+/// Writes one JSON object per line, with `t`, `kind`, and the `object` and
+/// `type` it happened to. Objects are numbered in the order they are first
+/// seen, so that runs of the same content give the same log. The kinds are
+/// `construct`, `enter`, `resume`, `exit`, `state`, `wait`, `cancel` and
+/// `extern`.
+pub struct Tracer {
+    sink: Box<dyn Write>,
+    // ids by object address, replaced whenever an address is reused
+    ids: HashMap<usize, usize>,
+    next_id: usize,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(sink: W) -> Self {
+        Tracer {
+            sink: Box::new(sink),
+            ids: HashMap::new(),
+            next_id: 0,
+            error: None,
+        }
+    }
+
+    /// The first write that failed, if any; nothing is written after one
+    /// fails.
+    pub fn error(self: &Self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(self: &mut Self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    pub(crate) fn record(
+        self: &mut Self,
+        totem: &Totem,
+        symbols: &Interner,
+        now: Time,
+        object: &data::Object,
+        kind: &str,
+        extra: &[(&str, Value)],
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+
+        // a constructor always means a new object, even at an old address
+        if kind == "construct" {
+            self.ids.remove(&data::address(object));
+        }
+        let mut line = String::new();
+        line.push_str("{\"t\":");
+        push_num(&mut line, f64::from(now));
+        line.push_str(",\"kind\":");
+        push_str(&mut line, kind);
+        line.push_str(",");
+        let names = Names { totem, symbols };
+        self.push_object(&mut line, &names, object, None);
+        for &(name, ref value) in extra {
+            line.push_str(",");
+            push_str(&mut line, name);
+            line.push_str(":");
+            self.push_value(&mut line, &names, value);
+        }
+        line.push_str("}\n");
+
+        if let Err(err) = self.sink.write_all(line.as_bytes()) {
+            self.error = Some(err);
+        }
+    }
+
+    fn id(self: &mut Self, object: &data::Object) -> usize {
+        let next_id = &mut self.next_id;
+        *self.ids.entry(data::address(object)).or_insert_with(|| {
+            *next_id += 1;
+            *next_id - 1
+        })
+    }
+
+    // writes the `"object":..,"type":..` pairs, without braces
+    fn push_object(
+        self: &mut Self,
+        line: &mut String,
+        names: &Names,
+        object: &data::Object,
+        interface: Option<Symbol>,
+    ) {
+        let id = self.id(object);
+        line.push_str(&format!("\"object\":{},\"type\":", id));
+        let type_name = object.borrow(names.totem).type_name;
+        push_str(line, names.symbols.name(type_name));
+        if let Some(interface) = interface {
+            line.push_str(",\"interface\":");
+            push_str(line, names.symbols.name(interface));
+        }
+    }
+
+    fn push_value(
+        self: &mut Self,
+        line: &mut String,
+        names: &Names,
+        value: &Value,
+    ) {
+        match *value {
+            Value::Str(text) => push_str(line, text),
+            Value::Symbol(symbol) => push_str(line, names.symbols.name(symbol)),
+            Value::Int(num) => line.push_str(&num.to_string()),
+            Value::Num(num) => push_num(line, num),
+            Value::Fields(fields) => {
+                line.push_str("[");
+                for (i, field) in fields.iter().enumerate() {
+                    if i > 0 {
+                        line.push_str(",");
+                    }
+                    self.push_field(line, names, field);
+                }
+                line.push_str("]");
+            },
+            Value::Data(fields) => self.push_data(line, names, fields),
+        }
+    }
+
+    fn push_field(
+        self: &mut Self,
+        line: &mut String,
+        names: &Names,
+        field: &data::Field,
+    ) {
+        match *field {
+            data::Field::Num(num) => push_num(line, num),
+            data::Field::TRef(ref object) => {
+                line.push_str("{");
+                self.push_object(line, names, object, None);
+                line.push_str("}");
+            },
+            data::Field::VRef(ref object) => {
+                line.push_str("{");
+                let table = Some(object.table);
+                self.push_object(line, names, &object.data, table);
+                line.push_str("}");
+            },
+            data::Field::Data(variant, ref fields) => {
+                line.push_str("{\"variant\":");
+                push_str(line, names.symbols.name(variant));
+                line.push_str(",\"fields\":");
+                self.push_data(line, names, fields);
+                line.push_str("}");
+            },
+            data::Field::Set(ref set) => {
+                line.push_str("{\"set\":[");
+                for (i, object) in set.iter().enumerate() {
+                    if i > 0 {
+                        line.push_str(",");
+                    }
+                    line.push_str("{");
+                    let table = Some(object.table);
+                    self.push_object(line, names, &object.data, table);
+                    line.push_str("}");
+                }
+                line.push_str("]}");
+            },
+        }
+    }
+
+    fn push_data(
+        self: &mut Self,
+        line: &mut String,
+        names: &Names,
+        fields: &data::Data,
+    ) {
+        line.push_str("{");
+        let fields = data::sorted_fields(names.symbols, fields);
+        for (i, (name, value)) in fields.into_iter().enumerate() {
+            if i > 0 {
+                line.push_str(",");
+            }
+            push_str(line, name);
+            line.push_str(":");
+            self.push_field(line, names, value);
+        }
+        line.push_str("}");
+    }
+}
+
+fn push_num(line: &mut String, num: f64) {
+    if num.is_finite() {
+        line.push_str(&num.to_string());
+    } else {
+        line.push_str("null");
+    }
+}
+
+fn push_str(line: &mut String, text: &str) {
+    line.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                line.push_str(&format!("\\u{:04x}", c as u32));
+            },
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
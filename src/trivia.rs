@@ -0,0 +1,64 @@
+/// A comment, with its delimiters.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Comment {
+    // byte offset of the start of the comment
+    pub offset: usize,
+    pub text: String,
+}
+
+/// Blanks out every comment in `text`, returning the code that is left and
+/// the comments in order, or the offset of a block comment that never ends.
+/// Block comments nest, and blanking leaves every token at its offset.
+pub fn strip(text: &str) -> Result<(String, Vec<Comment>), usize> {
+    let bytes = text.as_bytes();
+    let mut code = bytes.to_vec();
+    let mut comments = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let end = match (bytes[i], bytes.get(i + 1)) {
+            (b'/', Some(&b'/')) => {
+                text[i..].find('\n').map_or(text.len(), |n| i + n)
+            },
+            (b'/', Some(&b'*')) => block_end(bytes, i).ok_or(i)?,
+            _ => {
+                i += 1;
+                continue;
+            },
+        };
+        for byte in &mut code[i..end] {
+            // newlines stay, so that lines still line up
+            if *byte != b'\n' {
+                *byte = b' ';
+            }
+        }
+        comments.push(Comment { offset: i, text: text[i..end].into() });
+        i = end;
+    }
+
+    // only whole characters were replaced, and with ASCII
+    let code = String::from_utf8(code).expect("comment ended mid-character");
+    Ok((code, comments))
+}
+
+// the offset just past the block comment that starts at `start`
+fn block_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = start;
+    while i + 1 < bytes.len() {
+        match (bytes[i], bytes[i + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                i += 2;
+            },
+            (b'*', b'/') => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return Some(i);
+                }
+            },
+            _ => i += 1,
+        }
+    }
+    None
+}
@@ -0,0 +1,1359 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use prelude::*;
+
+use ast;
+use item;
+use item::{AlgorithmKind, FieldType};
+
+/// The static type of a value, as far as the checker can tell.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Ty {
+    // unannotated parameters, extern results, etc.
+    Unknown,
+    Num,
+    // the name of the data type, if known
+    Data(Option<String>),
+    TRef(String),
+    VRef(String, String),
+    Set,
+}
+
+impl Ty {
+    fn bool() -> Self {
+        Ty::Data(Some("Bool".into()))
+    }
+
+    fn is_num(self: &Self) -> bool {
+        match *self {
+            Ty::Num | Ty::Unknown => true,
+            _ => false,
+        }
+    }
+
+    fn is_data(self: &Self) -> bool {
+        match *self {
+            Ty::Data(_) | Ty::Unknown => true,
+            _ => false,
+        }
+    }
+
+    fn fits(self: &Self, expected: &Ty) -> bool {
+        match (self, expected) {
+            (&Ty::Unknown, _) | (_, &Ty::Unknown) => true,
+            (&Ty::Data(_), &Ty::Data(None)) => true,
+            (&Ty::Data(None), &Ty::Data(_)) => true,
+            _ => self == expected,
+        }
+    }
+
+    // the type a variable has after being assigned in two different branches
+    fn join(self: Self, other: &Ty) -> Ty {
+        if &self == other {
+            self
+        } else if self.is_data() && other.is_data() {
+            Ty::Data(None)
+        } else {
+            Ty::Unknown
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ty::Unknown => write!(f, "?"),
+            Ty::Num => write!(f, "Num"),
+            Ty::Data(None) => write!(f, "data"),
+            Ty::Data(Some(ref name)) => write!(f, "{}", name),
+            Ty::TRef(ref type_name) => write!(f, "{}", type_name),
+            Ty::VRef(ref type_name, ref interface_name) => {
+                write!(f, "{}.{}", type_name, interface_name)
+            },
+            Ty::Set => write!(f, "Set"),
+        }
+    }
+}
+
+pub struct TypeError {
+    // empty for declarations outside any class
+    pub type_name: String,
+    pub algorithm_name: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.type_name.len() == 0 {
+            return write!(
+                f,
+                "{}: in {}: {}",
+                self.line,
+                self.algorithm_name,
+                self.message,
+            );
+        }
+        write!(
+            f,
+            "{}: in {}.{}: {}",
+            self.line,
+            self.type_name,
+            self.algorithm_name,
+            self.message,
+        )
+    }
+}
+
+/// Every declared data type, and the variants that belong to each.
+pub struct DataTypes {
+    // variant name -> (data type name, fields), for types declared with
+    // `data`
+    variants: Dict<(String, Vec<(String, FieldType)>)>,
+    // `Type.State` -> state name -> fields, kept apart for each class so
+    // that two classes can both have a state of the same name
+    states: Dict<Dict<Vec<(String, FieldType)>>>,
+    // data type name -> variant names, in declaration order, including
+    // each class's states
+    types: Dict<Vec<String>>,
+    // states that may only be assigned right before a wait, as
+    // (`Type.State`, state name)
+    wait_bound: HashSet<(String, String)>,
+}
+
+/// The name of the data type made up of a class's states.
+pub fn state_type(type_name: &str) -> String {
+    format!("{}.State", type_name)
+}
+
+impl DataTypes {
+    pub fn new() -> Self {
+        let mut variants = Dict::new();
+        let mut types = Dict::new();
+
+        // booleans are ordinary data, declared here rather than in a class
+        let bool_variants = vec!["True".to_string(), "False".to_string()];
+        for variant in &bool_variants {
+            variants.insert(variant.clone(), ("Bool".into(), Vec::new()));
+        }
+        types.insert("Bool".into(), bool_variants);
+
+        let states = Dict::new();
+        let wait_bound = HashSet::new();
+        DataTypes { variants, states, types, wait_bound }
+    }
+
+    /// Adds the data and state declarations of a class, or with an empty
+    /// `type_name` the data declared outside any class, reporting any data
+    /// type or variant that was already declared elsewhere.
+    pub fn declare(
+        self: &mut Self,
+        type_name: &String,
+        items: &[(String, item::Item)],
+        lines: &ast::LineIndex,
+    ) -> Vec<TypeError> {
+        let mut errors = Vec::new();
+        let state_name = state_type(type_name);
+        let mut states = Vec::new();
+        for &(ref item_name, ref item) in items {
+            match *item {
+                item::Item::Data(ref decl) => {
+                    let line = lines.line(decl.offset);
+                    if self.types.contains_key(item_name) {
+                        errors.push(TypeError {
+                            type_name: type_name.clone(),
+                            algorithm_name: item_name.clone(),
+                            line,
+                            message: format!(
+                                "data type '{}' is already declared",
+                                item_name,
+                            ),
+                        });
+                        continue;
+                    }
+                    let mut names = Vec::with_capacity(decl.variants.len());
+                    for variant in &decl.variants {
+                        if let Some(&(ref other, _)) =
+                            self.variants.get(&variant.name)
+                        {
+                            errors.push(TypeError {
+                                type_name: type_name.clone(),
+                                algorithm_name: item_name.clone(),
+                                line,
+                                message: format!(
+                                    "variant '{}' is already declared in '{}'",
+                                    variant.name,
+                                    other,
+                                ),
+                            });
+                            continue;
+                        }
+                        let fields = variant.fields.clone();
+                        self.variants.insert(
+                            variant.name.clone(),
+                            (item_name.clone(), fields),
+                        );
+                        names.push(variant.name.clone());
+                    }
+                    self.types.insert(item_name.clone(), names);
+                },
+                item::Item::State { offset, wait_bound, ref variant } => {
+                    let class_states = self.states
+                        .entry(state_name.clone())
+                        .or_insert_with(Dict::new);
+                    if class_states.contains_key(&variant.name) {
+                        errors.push(TypeError {
+                            type_name: type_name.clone(),
+                            algorithm_name: item_name.clone(),
+                            line: lines.line(offset),
+                            message: format!(
+                                "state '{}' is already declared",
+                                variant.name,
+                            ),
+                        });
+                        continue;
+                    }
+                    let fields = variant.fields.clone();
+                    class_states.insert(variant.name.clone(), fields);
+                    if wait_bound {
+                        let state = (state_name.clone(), variant.name.clone());
+                        self.wait_bound.insert(state);
+                    }
+                    states.push(variant.name.clone());
+                },
+                _ => (),
+            }
+        }
+        if states.len() > 0 {
+            self.types.insert(state_name, states);
+        }
+        errors
+    }
+
+    // the data type and fields of a variant, looking at the states in
+    // `state_name` before the types declared with `data`
+    fn variant(
+        self: &Self,
+        state_name: &str,
+        name: &str,
+    ) -> Option<(&str, &Vec<(String, FieldType)>)> {
+        let states = self.states.get_key_value(state_name);
+        if let Some((data_name, states)) = states {
+            if let Some(fields) = states.get(name) {
+                return Some((data_name, fields));
+            }
+        }
+        self.variants
+            .get(name)
+            .map(|&(ref data_name, ref fields)| (data_name.as_str(), fields))
+    }
+
+    /// The type of a value annotated with `field`.
+    pub fn ty(self: &Self, field: &FieldType) -> Ty {
+        match *field {
+            FieldType::Num => Ty::Num,
+            FieldType::VRef { ref type_name, ref interface_name } => {
+                Ty::VRef(type_name.clone(), interface_name.clone())
+            },
+            FieldType::TRef(ref name) => {
+                if name == "Set" {
+                    Ty::Set
+                } else if self.types.contains_key(name) {
+                    Ty::Data(Some(name.clone()))
+                } else {
+                    Ty::TRef(name.clone())
+                }
+            },
+        }
+    }
+}
+
+/// The parts of a class that other classes' code can see.
+pub struct Class<'a> {
+    algorithms: Dict<(AlgorithmKind, &'a ast::Algorithm)>,
+    interfaces: Dict<&'a Dict<String>>,
+}
+
+impl<'a> Class<'a> {
+    pub fn new(items: &'a [(String, item::Item)]) -> Self {
+        let mut algorithms = Dict::new();
+        let mut interfaces = Dict::new();
+        for &(ref name, ref item) in items {
+            match *item {
+                item::Item::Function(ref alg) => {
+                    let sig = (AlgorithmKind::Function, alg);
+                    algorithms.insert(name.clone(), sig);
+                },
+                item::Item::Constructor(ref alg) => {
+                    let sig = (AlgorithmKind::Constructor, alg);
+                    algorithms.insert(name.clone(), sig);
+                },
+                item::Item::Interface { ref implementors, .. } => {
+                    interfaces.insert(name.clone(), implementors);
+                },
+                item::Item::Role(_)
+                    | item::Item::Data(_)
+                    | item::Item::State { .. } => (),
+            }
+        }
+        Class { algorithms, interfaces }
+    }
+}
+
+/// Checks the body of every algorithm in a class.
+pub fn check_class(
+    classes: &Dict<Class>,
+    data_types: &DataTypes,
+    type_name: &String,
+    items: &[(String, item::Item)],
+    lines: &ast::LineIndex,
+) -> Vec<TypeError> {
+    let mut errors = Vec::new();
+
+    // algorithms share one namespace, interfaces another
+    let mut algorithms = HashSet::new();
+    let mut interfaces = HashSet::new();
+    for &(ref name, ref item) in items {
+        let (offset, declared, what) = match *item {
+            item::Item::Function(ref alg)
+            | item::Item::Constructor(ref alg) => {
+                (alg.offset, &mut algorithms, "algorithm")
+            },
+            item::Item::Interface { offset, .. } => {
+                (offset, &mut interfaces, "interface")
+            },
+            _ => continue,
+        };
+        if !declared.insert(name) {
+            errors.push(TypeError {
+                type_name: type_name.clone(),
+                algorithm_name: name.clone(),
+                line: lines.line(offset),
+                message: format!("{} '{}' is already declared", what, name),
+            });
+        }
+    }
+
+    for &(ref name, ref item) in items {
+        let alg = match *item {
+            item::Item::Function(ref alg) => alg,
+            item::Item::Constructor(ref alg) => alg,
+            _ => continue,
+        };
+
+        let mut vars = Dict::new();
+        for (param, ty) in alg.param_list.iter().zip(&alg.param_types) {
+            let ty = ty.as_ref().map_or(Ty::Unknown, |ty| data_types.ty(ty));
+            vars.insert(param.clone(), ty);
+        }
+
+        let mut checker = Checker {
+            classes,
+            data_types,
+            lines,
+            type_name,
+            algorithm_name: name,
+            return_types: alg.return_types.as_ref(),
+            vars,
+            line: 0,
+            errors: &mut errors,
+        };
+        checker.check_block(&alg.steps);
+    }
+    errors
+}
+
+struct Checker<'a, 'b: 'a> {
+    classes: &'a Dict<Class<'b>>,
+    data_types: &'a DataTypes,
+    lines: &'a ast::LineIndex,
+    type_name: &'a String,
+    algorithm_name: &'a String,
+    return_types: Option<&'a Vec<FieldType>>,
+
+    vars: Dict<Ty>,
+    // line of the statement being checked
+    line: usize,
+    errors: &'a mut Vec<TypeError>,
+}
+
+impl<'a, 'b> Checker<'a, 'b> {
+    fn error(self: &mut Self, message: String) {
+        self.errors.push(TypeError {
+            type_name: self.type_name.clone(),
+            algorithm_name: self.algorithm_name.clone(),
+            line: self.line,
+            message,
+        });
+    }
+
+    fn check_block(self: &mut Self, block: &ast::Block) {
+        for (i, &(offset, ref statement)) in block.iter().enumerate() {
+            self.line = self.lines.line(offset);
+            if let ast::Statement::State(ref state) = *statement {
+                let next = block.get(i + 1).map(|&(_, ref next)| next);
+                self.check_wait_bound(state, next);
+            }
+            self.check_statement(statement);
+        }
+    }
+
+    // wait-bound states can only be assigned immediately before a wait, so
+    // that an object in one of them is always suspended at that wait
+    fn check_wait_bound(
+        self: &mut Self,
+        state: &ast::Expression,
+        next: Option<&ast::Statement>,
+    ) {
+        let (name, literal) = match *state {
+            ast::Expression::Data { ref name, .. } => {
+                let state = (state_type(self.type_name), name.clone());
+                if !self.data_types.wait_bound.contains(&state) {
+                    return;
+                }
+                (name.clone(), true)
+            },
+            // anything else only has the type of the class's states, so it
+            // may be any of them
+            _ => match self.wait_bound_state() {
+                Some(name) => (name, false),
+                None => return,
+            },
+        };
+        let is_wait = match next {
+            Some(&ast::Statement::Evaluate { ref expressions, ref results }) => {
+                results.len() == 0 && expressions.len() == 1 && {
+                    match expressions[0] {
+                        ast::Expression::Method { ref names, .. } => {
+                            names.len() == 1 && names[0] == "wait"
+                        },
+                        _ => false,
+                    }
+                }
+            },
+            _ => false,
+        };
+        if is_wait {
+            return;
+        }
+        if literal {
+            self.error(format!(
+                "wait state {} must be followed immediately by wait(..)",
+                name,
+            ));
+        } else {
+            self.error(format!(
+                "self.data may be set to wait state {} here, so it must be \
+                    followed immediately by wait(..)",
+                name,
+            ));
+        }
+    }
+
+    // one of this class's wait-bound states, if it has any
+    fn wait_bound_state(self: &Self) -> Option<String> {
+        let state_name = state_type(self.type_name);
+        let states = self.data_types.types.get(&state_name)?;
+        states
+            .iter()
+            .find(|state| {
+                let state = (state_name.clone(), state.to_string());
+                self.data_types.wait_bound.contains(&state)
+            })
+            .cloned()
+    }
+
+    // checks a block that may or may not run, merging any variables it
+    // assigns into `merged`
+    fn check_branch(
+        self: &mut Self,
+        before: &Dict<Ty>,
+        block: &ast::Block,
+        merged: &mut Dict<Ty>,
+    ) {
+        self.vars = before.clone();
+        self.check_block(block);
+        for (name, ty) in self.vars.drain() {
+            let joined = match merged.remove(&name) {
+                Some(old) => old.join(&ty),
+                None => ty,
+            };
+            merged.insert(name, joined);
+        }
+    }
+
+    fn check_statement(self: &mut Self, statement: &ast::Statement) {
+        use ast::Statement::*;
+        match *statement {
+            Bang => (),
+            Evaluate { ref expressions, ref results } => {
+                // `= a, b;` assigns to nothing, and is one statement where
+                // two were probably meant
+                if results.len() == 0 && expressions.len() != 1 {
+                    self.error(
+                        "separate statements with ';', not ','".into(),
+                    );
+                    return;
+                }
+                if results.len() == 0 && expressions.len() == 1 {
+                    if let ast::Expression::Method {
+                        ref names,
+                        ref args,
+                    } = expressions[0] {
+                        if self.check_builtin(names, args) {
+                            return;
+                        }
+                    }
+                }
+
+                let mut tys = Some(Vec::new());
+                for expression in expressions {
+                    let expr_tys = self.expression_types(expression);
+                    tys = match (tys, expr_tys) {
+                        (Some(mut tys), Some(expr_tys)) => {
+                            tys.extend(expr_tys);
+                            Some(tys)
+                        },
+                        _ => None,
+                    };
+                }
+
+                if results.len() == 0 {
+                    return;
+                }
+                match tys {
+                    Some(tys) => {
+                        if tys.len() != results.len() {
+                            self.error(format!(
+                                "{} value(s) assigned to {} variable(s)",
+                                tys.len(),
+                                results.len(),
+                            ));
+                        }
+                        let mut tys = tys.into_iter();
+                        for name in results {
+                            let ty = tys.next().unwrap_or(Ty::Unknown);
+                            self.vars.insert(name.clone(), ty);
+                        }
+                    },
+                    None => {
+                        for name in results {
+                            self.vars.insert(name.clone(), Ty::Unknown);
+                        }
+                    },
+                }
+            },
+            State(ref state) => {
+                let ty = self.expression_type(state);
+                let state_name = state_type(self.type_name);
+                if !self.data_types.types.contains_key(&state_name) {
+                    self.error(format!(
+                        "self.data is assigned, but {} declares no states",
+                        self.type_name,
+                    ));
+                } else if !ty.fits(&Ty::Data(Some(state_name))) {
+                    self.error(format!(
+                        "{} is not a state of {}",
+                        ty,
+                        self.type_name,
+                    ));
+                }
+            },
+            WhileLoop { ref condition, ref block } => {
+                self.check_bool(condition, "condition");
+                let before = self.vars.clone();
+                let mut merged = before.clone();
+                self.check_branch(&before, block, &mut merged);
+                self.vars = merged;
+            },
+            Branch { ref if_branches, ref else_branch } => {
+                let before = self.vars.clone();
+                let mut merged = Dict::new();
+                for &(ref condition, ref block) in if_branches {
+                    self.vars = before.clone();
+                    self.check_bool(condition, "condition");
+                    self.check_branch(&before, block, &mut merged);
+                }
+                self.check_branch(&before, else_branch, &mut merged);
+                self.vars = merged;
+            },
+            Match { ref data, ref arms, ref def } => {
+                let ty = self.expression_type(data);
+                if !ty.is_data() {
+                    self.error(format!("cannot match on {}", ty));
+                }
+                let state_name = self.match_states(&ty);
+                self.check_match_arms(&state_name, ty, arms, def.is_some());
+
+                let before = self.vars.clone();
+                let mut merged = Dict::new();
+                for &(ref variant, ref fields, ref block) in arms {
+                    let mut arm_vars = before.clone();
+                    for field in fields {
+                        let ty = self.field_type(&state_name, variant, field);
+                        arm_vars.insert(field.clone(), ty);
+                    }
+                    self.check_branch(&arm_vars, block, &mut merged);
+                }
+                if let Some(ref def) = *def {
+                    self.check_branch(&before, def, &mut merged);
+                } else {
+                    self.check_branch(&before, &Vec::new(), &mut merged);
+                }
+                self.vars = merged;
+            },
+        }
+    }
+
+    fn check_data(
+        self: &mut Self,
+        name: &String,
+        fields: &Vec<(String, ast::Expression)>,
+    ) -> Ty {
+        let data_types = self.data_types;
+        let state_name = state_type(self.type_name);
+        let variant = data_types.variant(&state_name, name);
+        let (data_name, declared) = match variant {
+            Some(variant) => variant,
+            None => {
+                self.error(format!("unknown data variant '{}'", name));
+                for &(_, ref field) in fields {
+                    self.expression_type(field);
+                }
+                return Ty::Data(None);
+            },
+        };
+
+        for &(ref field_name, ref field) in fields {
+            let ty = self.expression_type(field);
+            let declared_ty = declared
+                .iter()
+                .find(|&&(ref declared_name, _)| declared_name == field_name)
+                .map(|&(_, ref field_ty)| data_types.ty(field_ty));
+            match declared_ty {
+                Some(expected) => if !ty.fits(&expected) {
+                    self.error(format!(
+                        "field '{}' of {} expects {}, got {}",
+                        field_name,
+                        name,
+                        expected,
+                        ty,
+                    ));
+                },
+                None => self.error(format!(
+                    "{} has no field '{}'",
+                    name,
+                    field_name,
+                )),
+            }
+        }
+        for &(ref declared_name, _) in declared {
+            let given = fields.iter().any(|&(ref name, _)| name == declared_name);
+            if !given {
+                self.error(format!(
+                    "{} is missing field '{}'",
+                    name,
+                    declared_name,
+                ));
+            }
+        }
+
+        Ty::Data(Some(data_name.into()))
+    }
+
+    // the states that variant names in a match on `ty` refer to first: the
+    // ones `ty` is made of if it is some class's states, or else this class's
+    fn match_states(self: &Self, ty: &Ty) -> String {
+        match *ty {
+            Ty::Data(Some(ref name))
+                if self.data_types.states.contains_key(name) =>
+            {
+                name.clone()
+            },
+            _ => state_type(self.type_name),
+        }
+    }
+
+    // the declared type of a field bound by a match arm
+    fn field_type(
+        self: &Self,
+        state_name: &str,
+        variant: &str,
+        field: &str,
+    ) -> Ty {
+        self.data_types.variant(state_name, variant)
+            .and_then(|(_, fields)| {
+                fields.iter().find(|&&(ref name, _)| name == field)
+            })
+            .map_or(Ty::Unknown, |&(_, ref ty)| self.data_types.ty(ty))
+    }
+
+    fn check_match_arms(
+        self: &mut Self,
+        state_name: &str,
+        ty: Ty,
+        arms: &Vec<(String, Vec<String>, ast::Block)>,
+        has_default: bool,
+    ) {
+        let data_types = self.data_types;
+        let mut data_name = match ty {
+            Ty::Data(Some(name)) => Some(name),
+            _ => None,
+        };
+
+        for &(ref variant, ref fields, _) in arms {
+            let found = data_types.variant(state_name, variant);
+            let (arm_data, declared) = match found {
+                Some(found) => found,
+                None => {
+                    self.error(format!("unknown data variant '{}'", variant));
+                    continue;
+                },
+            };
+            match data_name {
+                Some(ref data_name) if data_name != arm_data => {
+                    self.error(format!(
+                        "arm {} is a {}, but the match is on {}",
+                        variant,
+                        arm_data,
+                        data_name,
+                    ));
+                    continue;
+                },
+                _ => (),
+            }
+            data_name = Some(arm_data.into());
+            for field in fields {
+                if !declared.iter().any(|&(ref name, _)| name == field) {
+                    self.error(format!(
+                        "{} has no field '{}'",
+                        variant,
+                        field,
+                    ));
+                }
+            }
+        }
+
+        if has_default {
+            return;
+        }
+        let data_name = match data_name {
+            Some(data_name) => data_name,
+            None => return,
+        };
+        let missing: Vec<&str> = data_types.types[&data_name]
+            .iter()
+            .filter(|variant| !arms.iter().any(|arm| &arm.0 == *variant))
+            .map(|variant| variant.as_str())
+            .collect();
+        if missing.len() > 0 {
+            self.error(format!(
+                "match on {} does not cover {}",
+                data_name,
+                missing.join(", "),
+            ));
+        }
+    }
+
+    fn check_bool(self: &mut Self, expression: &ast::Expression, what: &str) {
+        let ty = self.expression_type(expression);
+        if !ty.fits(&Ty::bool()) {
+            self.error(format!("{} must be True or False, not {}", what, ty));
+        }
+    }
+
+    // print, wait, return and trace, when used as statements
+    fn check_builtin(
+        self: &mut Self,
+        names: &Vec<String>,
+        args: &Vec<ast::Expression>,
+    ) -> bool {
+        if names.len() != 1 {
+            return false;
+        }
+        let name = names[0].as_str();
+        match name {
+            "print" => {
+                // anything can be printed
+                for arg in args {
+                    self.expression_type(arg);
+                }
+            },
+            "wait" => {
+                if args.len() != 1 {
+                    self.error("wait expects 1 argument".into());
+                }
+                for arg in args {
+                    let ty = self.expression_type(arg);
+                    if !ty.is_num() {
+                        self.error(format!("cannot wait for {}", ty));
+                    }
+                }
+            },
+            "trace" => {
+                if args.len() != 0 {
+                    self.error("trace expects no arguments".into());
+                }
+            },
+            "return" => {
+                let mut tys = Vec::with_capacity(args.len());
+                let mut known = true;
+                for arg in args {
+                    match self.expression_types(arg) {
+                        Some(arg_tys) => tys.extend(arg_tys),
+                        None => known = false,
+                    }
+                }
+                if let Some(expected) = self.return_types {
+                    if known && tys.len() != expected.len() {
+                        self.error(format!(
+                            "returned {} value(s), but {} declared",
+                            tys.len(),
+                            expected.len(),
+                        ));
+                    }
+                    for (ty, field) in tys.iter().zip(expected) {
+                        let expected = self.data_types.ty(field);
+                        if !ty.fits(&expected) {
+                            self.error(format!(
+                                "returned {} where {} was declared",
+                                ty,
+                                expected,
+                            ));
+                        }
+                    }
+                }
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn expression_type(self: &mut Self, expression: &ast::Expression) -> Ty {
+        match self.expression_types(expression) {
+            Some(mut tys) => {
+                if tys.len() != 1 {
+                    self.error(format!(
+                        "expected a single value, found {}",
+                        tys.len(),
+                    ));
+                    return Ty::Unknown;
+                }
+                tys.pop().unwrap()
+            },
+            None => Ty::Unknown,
+        }
+    }
+
+    // `None` means even the number of results is unknown
+    fn expression_types(
+        self: &mut Self,
+        expression: &ast::Expression,
+    ) -> Option<Vec<Ty>> {
+        use ast::Expression::*;
+        let ty = match *expression {
+            Var(ref name) => match self.vars.get(name) {
+                Some(ty) => ty.clone(),
+                None => {
+                    self.error(format!("unknown variable '{}'", name));
+                    Ty::Unknown
+                },
+            },
+            Method { ref names, ref args } => {
+                return self.check_method(names, args);
+            },
+            SelfObject => Ty::TRef(self.type_name.clone()),
+            SelfData => {
+                let state_name = state_type(self.type_name);
+                if self.data_types.types.contains_key(&state_name) {
+                    Ty::Data(Some(state_name))
+                } else {
+                    Ty::Data(None)
+                }
+            },
+            Data { ref name, ref fields } => {
+                return Some(vec![self.check_data(name, fields)]);
+            },
+
+            Const(_) => Ty::Num,
+            Bool(_) => Ty::bool(),
+            Comparison(ref x, ref ys) => {
+                self.check_num(x, "compare");
+                for &(_, ref y) in ys {
+                    self.check_num(y, "compare");
+                }
+                Ty::bool()
+            },
+            And(ref x, ref y) | Or(ref x, ref y) => {
+                let what = match *expression {
+                    And(..) => "operand of 'and'",
+                    _ => "operand of 'or'",
+                };
+                self.check_bool(x, what);
+                self.check_bool(y, what);
+                Ty::bool()
+            },
+            Not(ref x) => {
+                self.check_bool(x, "operand of 'not'");
+                Ty::bool()
+            },
+            Neg(ref x) => {
+                self.check_num(x, "negate");
+                Ty::Num
+            },
+            Add(ref x, ref y) | Sub(ref x, ref y) | Mul(ref x, ref y)
+                | Div(ref x, ref y) | Mod(ref x, ref y) | Pow(ref x, ref y) =>
+            {
+                self.check_num(x, "do arithmetic on");
+                self.check_num(y, "do arithmetic on");
+                Ty::Num
+            },
+        };
+        Some(vec![ty])
+    }
+
+    fn check_num(self: &mut Self, expression: &ast::Expression, verb: &str) {
+        let ty = self.expression_type(expression);
+        if !ty.is_num() {
+            self.error(format!("cannot {} {}", verb, ty));
+        }
+    }
+
+    fn argument_types(
+        self: &mut Self,
+        args: &Vec<ast::Expression>,
+    ) -> Option<Vec<Ty>> {
+        let mut tys = Vec::with_capacity(args.len());
+        let mut known = true;
+        for arg in args {
+            match self.expression_types(arg) {
+                Some(arg_tys) => tys.extend(arg_tys),
+                None => known = false,
+            }
+        }
+        if known { Some(tys) } else { None }
+    }
+
+    fn check_method(
+        self: &mut Self,
+        names: &Vec<String>,
+        args: &Vec<ast::Expression>,
+    ) -> Option<Vec<Ty>> {
+        let arg_tys = self.argument_types(args);
+
+        if names.len() == 1 {
+            // Interface(object)
+            let interface_name = &names[0];
+            let arg_tys = arg_tys?;
+            if arg_tys.len() != 1 {
+                self.error(format!(
+                    "{}(..) expects 1 argument, got {}",
+                    interface_name,
+                    arg_tys.len(),
+                ));
+                return None;
+            }
+            return match arg_tys[0] {
+                Ty::TRef(ref type_name) => {
+                    let interface = self.classes.get(type_name)
+                        .and_then(|class| class.interfaces.get(interface_name));
+                    if interface.is_none() {
+                        self.error(format!(
+                            "type '{}' has no interface '{}'",
+                            type_name,
+                            interface_name,
+                        ));
+                        return None;
+                    }
+                    let ty = Ty::VRef(type_name.clone(), interface_name.clone());
+                    Some(vec![ty])
+                },
+                Ty::Unknown => None,
+                ref other => {
+                    self.error(format!(
+                        "{}(..) expects a concrete object, not {}",
+                        interface_name,
+                        other,
+                    ));
+                    None
+                },
+            };
+        }
+
+        if names.len() == 3 {
+            // Type.Interface.ctor(..)
+            let (type_name, interface_name) = (&names[0], &names[1]);
+            let alg_name = self.classes.get(type_name)
+                .and_then(|class| class.interfaces.get(interface_name))
+                .and_then(|interface| interface.get(&names[2]))
+                .cloned();
+            let alg_name = match alg_name {
+                Some(alg_name) => alg_name,
+                None => {
+                    self.error(format!(
+                        "'{}' is not a constructor",
+                        names.join("."),
+                    ));
+                    return None;
+                },
+            };
+            self.check_call(
+                type_name,
+                &alg_name,
+                AlgorithmKind::Constructor,
+                arg_tys,
+            );
+            let ty = Ty::VRef(type_name.clone(), interface_name.clone());
+            return Some(vec![ty]);
+        }
+
+        if names.len() != 2 {
+            self.error(format!("'{}' is too long a path", names.join(".")));
+            return None;
+        }
+
+        let (object_name, method_name) = (&names[0], &names[1]);
+        if object_name == "Set" && method_name == "new" {
+            if args.len() != 0 {
+                self.error("Set.new expects no arguments".into());
+            }
+            return Some(vec![Ty::Set]);
+        }
+        if object_name == "game" {
+            return None;
+        }
+
+        let object_ty = if object_name == "self" {
+            Ty::TRef(self.type_name.clone())
+        } else if let Some(ty) = self.vars.get(object_name) {
+            ty.clone()
+        } else if self.classes.contains_key(object_name) {
+            // Type.ctor(..)
+            self.check_call(
+                object_name,
+                method_name,
+                AlgorithmKind::Constructor,
+                arg_tys,
+            );
+            return Some(vec![Ty::TRef(object_name.clone())]);
+        } else {
+            self.error(format!(
+                "unknown variable or type '{}'",
+                object_name,
+            ));
+            return None;
+        };
+
+        match object_ty {
+            Ty::TRef(ref type_name) => self.check_call(
+                type_name,
+                method_name,
+                AlgorithmKind::Function,
+                arg_tys,
+            ),
+            Ty::VRef(ref type_name, ref interface_name) => {
+                let alg_name = self.classes.get(type_name)
+                    .and_then(|class| class.interfaces.get(interface_name))
+                    .and_then(|interface| interface.get(method_name))
+                    .cloned();
+                match alg_name {
+                    Some(alg_name) => self.check_call(
+                        type_name,
+                        &alg_name,
+                        AlgorithmKind::Function,
+                        arg_tys,
+                    ),
+                    None => {
+                        self.error(format!(
+                            "interface '{}.{}' has no method '{}'",
+                            type_name,
+                            interface_name,
+                            method_name,
+                        ));
+                        None
+                    },
+                }
+            },
+            Ty::Set => self.check_set_method(method_name, arg_tys),
+            Ty::Unknown => None,
+            ref other => {
+                self.error(format!(
+                    "cannot call method '{}' on {}",
+                    method_name,
+                    other,
+                ));
+                None
+            },
+        }
+    }
+
+    fn check_set_method(
+        self: &mut Self,
+        method_name: &String,
+        arg_tys: Option<Vec<Ty>>,
+    ) -> Option<Vec<Ty>> {
+        let (param_count, result) = match method_name.as_str() {
+            "add" | "remove" => (1, vec![]),
+            "next" => (0, vec![Ty::Unknown]),
+            "not_empty" => (0, vec![Ty::bool()]),
+            _ => {
+                self.error(format!("sets have no method '{}'", method_name));
+                return None;
+            },
+        };
+        if let Some(arg_tys) = arg_tys {
+            if arg_tys.len() != param_count {
+                self.error(format!(
+                    "Set.{} expects {} argument(s), got {}",
+                    method_name,
+                    param_count,
+                    arg_tys.len(),
+                ));
+            }
+            for ty in arg_tys {
+                if let Ty::VRef(..) = ty { continue; }
+                if ty == Ty::Unknown { continue; }
+                self.error(format!("sets can only hold objects, not {}", ty));
+            }
+        }
+        Some(result)
+    }
+
+    // checks a call to a known algorithm, and returns what it gives back
+    fn check_call(
+        self: &mut Self,
+        type_name: &String,
+        alg_name: &String,
+        expected_kind: AlgorithmKind,
+        arg_tys: Option<Vec<Ty>>,
+    ) -> Option<Vec<Ty>> {
+        let found = self.classes.get(type_name)
+            .and_then(|class| class.algorithms.get(alg_name))
+            .cloned();
+        let (kind, alg) = match found {
+            Some(found) => found,
+            None => {
+                self.error(format!(
+                    "type '{}' has no algorithm '{}'",
+                    type_name,
+                    alg_name,
+                ));
+                return None;
+            },
+        };
+        if kind != expected_kind {
+            self.error(format!(
+                "{}.{} is a {}, not a {}",
+                type_name,
+                alg_name,
+                kind,
+                expected_kind,
+            ));
+        }
+
+        if let Some(arg_tys) = arg_tys {
+            if arg_tys.len() != alg.param_list.len() {
+                self.error(format!(
+                    "{}.{} expects {} argument(s), got {}",
+                    type_name,
+                    alg_name,
+                    alg.param_list.len(),
+                    arg_tys.len(),
+                ));
+            }
+            let params = alg.param_list.iter().zip(&alg.param_types);
+            for (ty, (param, field)) in arg_tys.iter().zip(params) {
+                let expected = match *field {
+                    Some(ref field) => self.data_types.ty(field),
+                    None => continue,
+                };
+                if !ty.fits(&expected) {
+                    self.error(format!(
+                        "argument '{}' of {}.{} expects {}, got {}",
+                        param,
+                        type_name,
+                        alg_name,
+                        expected,
+                        ty,
+                    ));
+                }
+            }
+        }
+
+        let data_types = self.data_types;
+        alg.return_types.as_ref().map(|tys| {
+            tys.iter().map(|ty| data_types.ty(ty)).collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prelude::*;
+
+    use ast;
+    use item;
+    use parser;
+
+    use super::{check_class, Class, DataTypes};
+
+    // the message of every type error in a file
+    fn errors(source: &str) -> Vec<String> {
+        let file = parser::FileParser::new().parse(source).unwrap();
+        let lines = ast::LineIndex::new(source);
+        let mut classes = Vec::new();
+        let mut data = Vec::new();
+        for (_, name, file_item) in file.items {
+            match file_item {
+                item::FileItem::Class(items) => classes.push((name, items)),
+                item::FileItem::Data(decl) => {
+                    data.push((name, item::Item::Data(decl)));
+                },
+                item::FileItem::Role(_) => (),
+            }
+        }
+        let signatures: Dict<Class> = classes
+            .iter()
+            .map(|&(ref name, ref items)| (name.clone(), Class::new(items)))
+            .collect();
+
+        let mut data_types = DataTypes::new();
+        let mut errors = data_types.declare(&String::new(), &data, &lines);
+        for &(ref name, ref items) in &classes {
+            errors.extend(data_types.declare(name, items, &lines));
+        }
+        for &(ref name, ref items) in &classes {
+            errors.extend(check_class(
+                &signatures,
+                &data_types,
+                name,
+                items,
+                &lines,
+            ));
+        }
+        errors.into_iter().map(|err| err.message).collect()
+    }
+
+    // a class with one state and an `init` constructor running `body`
+    fn errors_in(body: &str) -> Vec<String> {
+        errors(&format!(
+            "class Root {{
+                state Idle {{ n: Num }}
+                wait state Busy {{}}
+                ctor init() {{ {} }}
+                fun pair() -> (Num, Num) {{ return(1, 2); }}
+                fun twice(x: Num) -> Num {{ return(x * 2); }}
+            }}",
+            body,
+        ))
+    }
+
+    #[test]
+    fn accepts_correct_code() {
+        let found = errors_in("
+            self.data = Idle { n = 1 };
+            a, b = self.pair();
+            c = self.twice(a + b);
+            self.data = Busy {};
+            wait(c);
+        ");
+        assert_eq!(found, Vec::<String>::new());
+    }
+
+    #[test]
+    fn bad_arity() {
+        let found = errors_in("x = self.twice(1, 2);");
+        assert_eq!(found, vec!["Root.twice expects 1 argument(s), got 2"]);
+    }
+
+    #[test]
+    fn bad_field() {
+        let found = errors_in("self.data = Idle { n = 1, m = 2 };");
+        assert_eq!(found, vec!["Idle has no field 'm'"]);
+
+        let found = errors_in("self.data = Idle { n = Idle { n = 1 } };");
+        assert_eq!(
+            found,
+            vec!["field 'n' of Idle expects Num, got Root.State"],
+        );
+    }
+
+    #[test]
+    fn unknown_variant() {
+        let found = errors_in("self.data = Lost {};");
+        assert_eq!(found[0], "unknown data variant 'Lost'");
+    }
+
+    #[test]
+    fn wrong_return_count() {
+        let found = errors_in("a, b, c = self.pair();");
+        assert_eq!(found, vec!["2 value(s) assigned to 3 variable(s)"]);
+
+        let found = errors(
+            "class Root { fun f() -> Num { return(1, 2); } }",
+        );
+        assert_eq!(found, vec!["returned 2 value(s), but 1 declared"]);
+    }
+
+    #[test]
+    fn wait_bound_state() {
+        let found = errors_in("self.data = Busy {}; x = 1;");
+        assert_eq!(
+            found,
+            vec!["wait state Busy must be followed immediately by wait(..)"],
+        );
+
+        // a state held in a variable may be the wait-bound one
+        let found = errors_in("s = self.data; self.data = s; x = 1;");
+        assert_eq!(found, vec![
+            "self.data may be set to wait state Busy here, so it must be \
+                followed immediately by wait(..)",
+        ]);
+        let found = errors_in("s = self.data; self.data = s; wait(1);");
+        assert_eq!(found, Vec::<String>::new());
+    }
+
+    #[test]
+    fn states_belong_to_their_class() {
+        let found = errors("
+            data Mood { Idle {}, Happy {} }
+            class A {
+                state Idle { n: Num }
+                ctor init() {
+                    self.data = Idle { n = 1 };
+                    match self.data { Idle { n } => { x = n + 1; } }
+                    mood = Happy {};
+                }
+            }
+            class B {
+                state Idle {}
+                state Done {}
+                ctor init() {
+                    self.data = Idle {};
+                    match self.data { Idle {} => {}, Done {} => {} }
+                }
+            }
+        ");
+        assert_eq!(found, Vec::<String>::new());
+
+        let found = errors("class A { state Idle {} state Idle {} }");
+        assert_eq!(found, vec!["state 'Idle' is already declared"]);
+    }
+
+    #[test]
+    fn statements_separated_by_commas() {
+        let found = errors_in("= 1, 2;");
+        assert_eq!(found, vec!["separate statements with ';', not ','"]);
+    }
+
+    #[test]
+    fn algorithms_declared_twice() {
+        let found = errors_in("} fun go() {} fun go() {");
+        assert_eq!(found, vec!["algorithm 'go' is already declared"]);
+
+        // functions and constructors share names
+        let found = errors_in("} fun init() {");
+        assert_eq!(found, vec!["algorithm 'init' is already declared"]);
+    }
+}